@@ -1,117 +1,1574 @@
 use nom::{
-    branch::alt, bytes::complete::tag, character::complete as cc, combinator::map,
-    combinator::value, multi::many0, sequence::delimited, IResult,
+    branch::alt,
+    bytes::complete::{tag, take_while, take_while1},
+    character::complete::{self as cc, satisfy},
+    combinator::{all_consuming, map, map_opt, not, opt, recognize, value},
+    multi::{fold_many0, many0},
+    sequence::{delimited, pair, preceded, terminated},
+    IResult,
 };
 
-use num::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub, Rational64};
+use num::integer::Roots;
+use num::rational::Ratio;
+use num::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub, Rational64, Signed, ToPrimitive};
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
-use std::io::{stdin, stdout, Write};
+use std::fs;
+use std::io::{stdin, stdout, IsTerminal, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::Once;
+use std::time::{Duration, Instant};
 
 fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        print!("{USAGE}");
+        return Ok(());
+    }
+    if args.iter().any(|arg| arg == "--version" || arg == "-V") {
+        println!("calc-rs {}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+
+    let settings = Settings::from_args(args)?;
+    #[cfg(feature = "rand")]
+    let settings = Settings {
+        seed: resolve_seed(settings.seed, std::env::var("CALC_SEED").ok())?,
+        ..settings
+    };
+
     let stdin = stdin();
     let mut stdout = stdout();
 
+    let prompt = resolve_prompt(settings.prompt.clone(), std::env::var("CALC_PROMPT").ok());
+    let init = settings.init.clone();
+
+    let sandbox = settings.sandbox;
+
+    let mut calculator = Calculator::new(settings);
+    match init {
+        Some(path) => calculator.load_init(&path),
+        None if sandbox => {}
+        None => {
+            if let Some(path) = default_init_path().filter(|path| path.exists()) {
+                calculator.load_init(&path)
+            }
+        }
+    }
+
+    // Failed assertions only abort scripts, not interactive sessions.
+    let interactive = stdin.is_terminal();
     let mut buffer = String::new();
-    let mut stack = Stack::new();
 
     loop {
-        print!("> ");
+        print!("{prompt}");
         stdout.flush().unwrap();
-        stdin.read_line(&mut buffer)?;
-
-        if buffer == "exit\n" {
-            exit(0)
-        }
-
-        match Line::parse(&buffer) {
-            Err(_) => println!("Parsing Error!"),
-            Ok((_, line)) => {
-                buffer.clear();
-                let calc_result = line.calc(stack.clone());
-                if let Some(returned_stack) = match calc_result {
-                    Ok(returned_stack) => {
-                        match returned_stack.last() {
-                            None => {}
-                            Some(a) => {
-                                println!("Stack: {returned_stack}, Result: {a}")
-                            }
-                        };
-                        Some(returned_stack)
-                    }
-                    Err(CalcError::NotEnoughItemsInStack) => {
-                        println!("Stack: {stack}, Not enough items in stack!");
-                        None
+        buffer.clear();
+        if stdin.read_line(&mut buffer)? == 0 || buffer.trim() == "exit" {
+            if !interactive && calculator.settings.result_line {
+                println!("{}", calculator.result_line());
+            }
+            return Ok(());
+        }
+
+        match calculator.run(&buffer) {
+            Ok(output) | Err(output) => print!("{output}"),
+        }
+
+        if !interactive && calculator.failed_assertions > 0 {
+            exit(1)
+        }
+    }
+}
+
+/// The state of a session: the stack, the current settings and any macros
+/// defined so far.
+#[derive(Debug, Default)]
+struct Calculator {
+    stack: Stack,
+    settings: Settings,
+    macros: HashMap<String, Line>,
+    /// Values saved with `store`, recalled by name.
+    variables: HashMap<String, Rational64>,
+    /// Previously run lines, oldest first, capped at `settings.history_size`.
+    history: VecDeque<String>,
+    /// Text printed by operators such as `.` during the current evaluation.
+    emitted: Vec<String>,
+    failed_assertions: usize,
+    #[cfg(feature = "rand")]
+    rng: Rng,
+}
+
+impl Calculator {
+    fn new(settings: Settings) -> Self {
+        Calculator {
+            #[cfg(feature = "rand")]
+            rng: settings.seed.map(Rng::new).unwrap_or_default(),
+            settings,
+            ..Default::default()
+        }
+    }
+
+    /// Runs one line of input, which is either a command such as `dump` or
+    /// `mode infix`, or an expression to evaluate. Returns the text to show
+    /// the user, as an `Err` if the line failed.
+    ///
+    /// Lines that succeed are remembered: `history` lists them numbered from
+    /// 1, oldest first, and `!n` runs entry `n` again.
+    fn run(&mut self, input: &str) -> Result<String, String> {
+        self.check_length(input)
+            .map_err(|e| format!("{}\n", e.report()))?;
+        let input = input.trim();
+        if input == "history" {
+            return Ok(self
+                .history
+                .iter()
+                .enumerate()
+                .map(|(n, line)| format!("{}: {line}\n", n + 1))
+                .collect());
+        }
+        if let Some(n) = input.strip_prefix('!') {
+            let line = n
+                .parse::<usize>()
+                .ok()
+                .and_then(|n| self.history.get(n.checked_sub(1)?))
+                .ok_or_else(|| format!("No history entry {n}\n"))?
+                .clone();
+            return self.run(&line);
+        }
+
+        if input == "reset" {
+            self.reset();
+            return Ok(String::new());
+        }
+
+        let result = self.run_command(input);
+        if result.is_ok() && !input.is_empty() && self.settings.history_size > 0 {
+            if self.history.len() == self.settings.history_size {
+                self.history.pop_front();
+            }
+            self.history.push_back(input.to_string());
+        }
+        result
+    }
+
+    /// Starts a clean session: empties the stack and forgets all macros,
+    /// variables, history and failed assertions. Settings (including anything changed
+    /// with commands such as `mode` or `exact`) and the random number
+    /// generator are kept.
+    fn reset(&mut self) {
+        *self = Calculator {
+            settings: std::mem::take(&mut self.settings),
+            #[cfg(feature = "rand")]
+            rng: self.rng.clone(),
+            ..Default::default()
+        };
+    }
+
+    fn run_command(&mut self, input: &str) -> Result<String, String> {
+        match input {
+            "mode infix" => self.settings.mode = Mode::Infix,
+            "mode rpn" => self.settings.mode = Mode::Rpn,
+            "display top" => self.settings.order = Order::TopFirst,
+            "display bottom" => self.settings.order = Order::BottomFirst,
+            "exact on" => self.settings.exact = true,
+            "exact off" => self.settings.exact = false,
+            "time on" => self.settings.time = true,
+            "time off" => self.settings.time = false,
+            "echo on" => self.settings.echo = true,
+            "echo off" => self.settings.echo = false,
+            "group on" => self.settings.group = true,
+            "group off" => self.settings.group = false,
+            "dump" => return Ok(self.stack.dump()),
+            "vars" => return Ok(self.list_variables()),
+            "defs" => return Ok(self.list_macros()),
+            "settings" => return Ok(self.settings.listing()),
+            "copy" => {
+                self.check_sandbox("copy")?;
+                return self.copy(set_clipboard);
+            }
+            "assert" => return self.assert(""),
+            _ => match input.split_once(char::is_whitespace) {
+                Some(("def", definition)) => return self.define(definition),
+                Some(("store", name)) => return self.store(name.trim()),
+                Some(("undef", name)) => {
+                    let name = name.trim();
+                    if self.macros.remove(name).is_none() {
+                        return Err(format!("No macro {name}\n"));
                     }
-                    Err(CalcError::MathError) => {
-                        println!("Stack: {stack}, Math Error!");
-                        None
+                }
+                Some(("unset", name)) => {
+                    let name = name.trim();
+                    if self.variables.remove(name).is_none() {
+                        return Err(format!("No variable {name}\n"));
                     }
-                } {
-                    stack = returned_stack
                 }
+                Some(("assert", expected)) => return self.assert(expected),
+                Some(("radix", n)) => {
+                    self.settings.radix = parse_radix(n.trim()).map_err(|e| format!("{e}\n"))?
+                }
+                Some(("maxdepth", n)) => {
+                    self.settings.max_depth = n
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("Invalid depth: {n}\n"))?
+                }
+                Some(("roundmode", mode)) => {
+                    self.settings.round_mode = mode.trim().parse().map_err(|e| format!("{e}\n"))?
+                }
+                _ => return self.evaluate(input),
+            },
+        }
+        Ok(String::new())
+    }
+
+    /// Handles `assert <values>`, checking that the stack holds exactly the
+    /// given values. The values are an RPN line evaluated on an empty stack,
+    /// so `assert 1 2 /` expects a single `1/2`. The stack itself is never
+    /// changed; failures are counted so batch runs can exit unsuccessfully.
+    fn assert(&mut self, expected: &str) -> Result<String, String> {
+        let expected = match Line::parse_with(expected, &self.settings) {
+            Ok(("", line)) => self
+                .calc_line(&line, Stack::new())
+                .map_err(|e| format!("{}\n", e.report()))?,
+            _ => {
+                return Err(format!(
+                    "{}\n",
+                    CalcError::ParseError(String::new()).report()
+                ))
+            }
+        };
+        if expected == self.stack {
+            return Ok("Assertion passed\n".to_string());
+        }
+        self.failed_assertions += 1;
+        let order = self.settings.order;
+        Err(format!(
+            "Assertion failed!\n- expected: {}\n+ actual:   {}\n",
+            expected.display(order),
+            self.stack.display(order)
+        ))
+    }
+
+    fn evaluate(&mut self, input: &str) -> Result<String, String> {
+        let line = self.parse(input).map_err(|e| format!("{}\n", e.report()))?;
+
+        let order = self.settings.order;
+        let (result, elapsed) = timed(self.settings.time, || self.eval_line(&line));
+        let timing = match elapsed {
+            Some(elapsed) => format!("Time: {elapsed:?}\n"),
+            None => String::new(),
+        };
+        // Anything emitted before an error is still shown, as it would have
+        // been printed already in a stack language like Forth.
+        let mut emitted: String = self.emitted.drain(..).map(|e| e + "\n").collect();
+        if self.settings.echo {
+            emitted = match result {
+                Ok(Some(a)) => format!("{line} => {a}\n{emitted}"),
+                _ => format!("{line}\n{emitted}"),
+            };
+        }
+        let group = self.settings.grouping();
+        let stack = self.stack.display(order).grouped(group);
+        match result {
+            Ok(None) => Ok(emitted + &timing),
+            Ok(Some(a)) => Ok(format!(
+                "{emitted}Stack: {stack}, Result: {}\n{timing}",
+                format_value(&a, group)
+            )),
+            Err(e) => Err(format!("{emitted}Stack: {stack}, {}\n{timing}", e.report())),
+        }
+    }
+
+    /// Rejects input longer than `max_input_length` before it reaches the
+    /// parser, which would otherwise build a huge line from it.
+    fn check_length(&self, input: &str) -> Result<(), CalcError> {
+        if input.len() > self.settings.max_input_length {
+            return Err(CalcError::InputTooLong(self.settings.max_input_length));
+        }
+        Ok(())
+    }
+
+    /// Parses an expression in the current input mode.
+    fn parse(&self, input: &str) -> Result<Line, CalcError> {
+        let parsed = match self.settings.mode {
+            Mode::Rpn => Line::parse_with(input, &self.settings),
+            Mode::Infix => Line::parse_infix(input),
+        };
+        let line = parsed.map(|(_, line)| line).map_err(|e| match e {
+            nom::Err::Error(e) | nom::Err::Failure(e) => CalcError::ParseError(e.input.to_string()),
+            nom::Err::Incomplete(_) => CalcError::ParseError(String::new()),
+        })?;
+        // The infix grammar builds its operators itself, so check them here.
+        for item in &line.0 {
+            if let Item::Operator(op) = item {
+                if !self.settings.allows(op) {
+                    return Err(CalcError::ParseError(op.symbol().to_string()));
+                }
+            }
+        }
+        Ok(line)
+    }
+
+    /// Parses and evaluates an expression (not a command), returning the new
+    /// top of the stack. The stack is left untouched if anything fails.
+    #[cfg(test)]
+    fn eval(&mut self, input: &str) -> Result<Option<Rational64>, CalcError> {
+        self.check_length(input)?;
+        let line = self.parse(input)?;
+        self.eval_line(&line)
+    }
+
+    /// Evaluates a parsed line, reporting the outcome as the new top of the
+    /// stack or the error that left it unchanged; `evaluate` turns this into
+    /// the text shown at the prompt.
+    fn eval_line(&mut self, line: &Line) -> Result<Option<Rational64>, CalcError> {
+        // The stack is only replaced once the line has succeeded, so it
+        // survives any error intact.
+        self.stack = self.calc_line(line, self.stack.clone())?;
+        Ok(self.stack.last().copied())
+    }
+
+    /// Expands `line` and evaluates it on `stack`. Every line a session runs
+    /// goes through here, including the macros `fold` and `map` call, so a
+    /// bug in one operator becomes an error instead of ending the session.
+    fn calc_line(&mut self, line: &Line, stack: Stack) -> Result<Stack, CalcError> {
+        let line = line.expand(&self.macros, &self.variables)?;
+        recover(|| line.calc_with(stack, self))
+    }
+
+    /// The line `--result-line` prints once a script has finished, e.g.
+    /// `RESULT: 9`.
+    fn result_line(&self) -> String {
+        let marker = &self.settings.result_marker;
+        match self.stack.last() {
+            Some(top) => format!("{marker} {top}"),
+            None => format!("{marker} <empty>"),
+        }
+    }
+
+    /// Fails with a message naming `command` if sandbox mode is on, for
+    /// commands that reach outside the calculator.
+    fn check_sandbox(&self, command: &str) -> Result<(), String> {
+        if self.settings.sandbox {
+            return Err(format!("{command} is disabled in sandbox mode\n"));
+        }
+        Ok(())
+    }
+
+    /// Handles `copy`, passing the top of the stack to `write` as it would be
+    /// shown as a result, e.g. `3/4`.
+    fn copy(&self, write: impl FnOnce(&str) -> Result<(), String>) -> Result<String, String> {
+        let top = self
+            .stack
+            .last()
+            .ok_or_else(|| "Nothing to copy\n".to_string())?;
+        let text = top.to_string();
+        write(&text).map_err(|e| format!("{e}\n"))?;
+        Ok(format!("Copied {text}\n"))
+    }
+
+    /// Handles `store <name>`, saving the top of the stack (without popping
+    /// it) so that `name` pushes it again. Like macros, variables are looked
+    /// up when a line is expanded, so a macro using one keeps the value it
+    /// had when the macro was defined.
+    fn store(&mut self, name: &str) -> Result<String, String> {
+        if !matches!(
+            Item::parse_with(name, &self.settings),
+            Ok(("", Item::Word(_)))
+        ) {
+            return Err(format!("Invalid variable name: {name}\n"));
+        }
+        let top = self
+            .stack
+            .last()
+            .ok_or_else(|| "Nothing to store\n".to_string())?;
+        self.variables.insert(name.to_string(), *top);
+        Ok(String::new())
+    }
+
+    /// Handles `vars`, listing each variable as `name = value`, sorted by
+    /// name.
+    fn list_variables(&self) -> String {
+        let mut variables: Vec<_> = self.variables.iter().collect();
+        variables.sort();
+        variables
+            .into_iter()
+            .map(|(name, value)| format!("{name} = {value}\n"))
+            .collect()
+    }
+
+    /// Handles `defs`, listing each macro as `name = body`, sorted by name.
+    /// Bodies are shown as stored, with any macros they used already
+    /// expanded.
+    fn list_macros(&self) -> String {
+        let mut macros: Vec<_> = self.macros.iter().collect();
+        macros.sort_by(|a, b| a.0.cmp(b.0));
+        macros
+            .into_iter()
+            .map(|(name, body)| format!("{name} = {body}\n"))
+            .collect()
+    }
+
+    /// Handles `def <name> <body>`, storing the RPN `body` under `name` so
+    /// that `name` can be used like an operator. Macros used in the body are
+    /// expanded straight away, so redefining them later does not change this
+    /// macro and a macro can never refer to itself.
+    fn define(&mut self, definition: &str) -> Result<String, String> {
+        let definition = definition.trim();
+        let (name, body) = definition
+            .split_once(char::is_whitespace)
+            .unwrap_or((definition, ""));
+        if !matches!(
+            Item::parse_with(name, &self.settings),
+            Ok(("", Item::Word(_)))
+        ) {
+            return Err(format!("Invalid macro name: {name}\n"));
+        }
+        let body = match Line::parse_with(body, &self.settings) {
+            Ok(("", body)) => body,
+            _ => {
+                return Err(format!(
+                    "{}\n",
+                    CalcError::ParseError(String::new()).report()
+                ))
+            }
+        };
+        let body = body
+            .expand(&self.macros, &self.variables)
+            .map_err(|e| format!("{}\n", e.report()))?;
+        self.macros.insert(name.to_string(), body);
+        Ok(String::new())
+    }
+
+    /// Runs every line of an init file as if it had been typed at the prompt,
+    /// skipping blank lines and `#` comments. Failures are reported on stderr
+    /// but never stop startup, and failed assertions aren't counted against
+    /// the script that follows.
+    fn load_init(&mut self, path: &Path) {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                eprintln!("Warning: could not read {}: {e}", path.display());
+                return;
             }
+        };
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Err(e) = self.run(line) {
+                eprintln!("Warning: {}:{}: {}", path.display(), n + 1, e.trim_end());
+            }
+        }
+        self.failed_assertions = 0;
+    }
+}
+
+/// Runs `f`, also measuring how long it took if `enabled`. When disabled the
+/// clock is never read.
+fn timed<T>(enabled: bool, f: impl FnOnce() -> T) -> (T, Option<Duration>) {
+    if !enabled {
+        return (f(), None);
+    }
+    let start = Instant::now();
+    let result = f();
+    (result, Some(start.elapsed()))
+}
+
+/// Puts `text` on the system clipboard by piping it to the platform's
+/// clipboard tool, so no extra dependencies are needed.
+#[cfg(feature = "clipboard")]
+fn set_clipboard(text: &str) -> Result<(), String> {
+    use std::process::{Command, Stdio};
+    let (program, args): (&str, &[&str]) = if cfg!(target_os = "macos") {
+        ("pbcopy", &[])
+    } else if cfg!(windows) {
+        ("clip", &[])
+    } else if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        ("wl-copy", &[])
+    } else {
+        ("xclip", &["-selection", "clipboard"])
+    };
+    let failed = |e: std::io::Error| format!("Could not run {program}: {e}");
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(failed)?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes()).map_err(failed)?;
+    }
+    match child.wait().map_err(failed)? {
+        status if status.success() => Ok(()),
+        status => Err(format!("{program} failed: {status}")),
+    }
+}
+
+/// Without the `clipboard` feature, `copy` only explains why it can't.
+#[cfg(not(feature = "clipboard"))]
+fn set_clipboard(_text: &str) -> Result<(), String> {
+    Err("Clipboard support not enabled (build with --features clipboard)".to_string())
+}
+
+/// `~/.calcrc`, which is loaded at startup when it exists and no `--init`
+/// file was given.
+fn default_init_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".calcrc"))
+}
+
+/// How a line of input is read: postfix (the default) or conventional infix.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+enum Mode {
+    #[default]
+    Rpn,
+    Infix,
+}
+
+/// How a value exactly between two integers, or any non-integer for the
+/// directed modes, is rounded.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+enum RoundMode {
+    /// Nearest, with halves going away from zero: `5/2` is 3, `-5/2` is -3.
+    #[default]
+    HalfUp,
+    /// Nearest, with halves going to the even neighbour: `5/2` is 2.
+    HalfEven,
+    /// Truncate: `7/2` is 3, `-7/2` is -3.
+    TowardZero,
+    /// The next integer further from zero: `1/3` is 1, `-1/3` is -1.
+    AwayFromZero,
+}
+
+impl std::str::FromStr for RoundMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half-up" => Ok(RoundMode::HalfUp),
+            "half-even" => Ok(RoundMode::HalfEven),
+            "toward-zero" => Ok(RoundMode::TowardZero),
+            "away-from-zero" => Ok(RoundMode::AwayFromZero),
+            _ => anyhow::bail!("Unknown rounding mode: {s}"),
         }
     }
 }
 
+impl fmt::Display for RoundMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            RoundMode::HalfUp => "half-up",
+            RoundMode::HalfEven => "half-even",
+            RoundMode::TowardZero => "toward-zero",
+            RoundMode::AwayFromZero => "away-from-zero",
+        })
+    }
+}
+
 #[derive(Debug, PartialEq)]
+struct Settings {
+    mode: Mode,
+    order: Order,
+    prompt: Option<String>,
+    /// Refuse to approximate results that have no exact rational form.
+    exact: bool,
+    init: Option<PathBuf>,
+    /// How many input lines `history` remembers.
+    history_size: usize,
+    /// Report how long each evaluation took.
+    time: bool,
+    /// Print each evaluated line back in canonical form before its result.
+    echo: bool,
+    /// Print the final top of the stack after a script, see
+    /// `Calculator::result_line`.
+    result_line: bool,
+    result_marker: String,
+    /// Group the digits of whole numbers in output into thousands.
+    group: bool,
+    group_separator: char,
+    /// The most values the stack may hold.
+    max_depth: usize,
+    /// The longest line, in bytes, that will be parsed.
+    max_input_length: usize,
+    /// The largest denominator an operator may leave on top of the stack,
+    /// or 0 for no limit.
+    denominator_limit: i64,
+    /// Require whitespace after every number, see `Item::parse_with`.
+    strict_tokens: bool,
+    /// The base, from 2 to 36, that numbers in input are written in.
+    radix: u32,
+    /// The only operators, by symbol, that input may use, if restricted.
+    enabled_ops: Option<HashSet<&'static str>>,
+    /// Operators, by symbol, that input may not use.
+    disabled_ops: HashSet<&'static str>,
+    /// Refuse anything that touches files or the clipboard, for running
+    /// untrusted input.
+    sandbox: bool,
+    /// How `round` and decimal output settle values that fall between two
+    /// candidates.
+    round_mode: RoundMode,
+    #[cfg(feature = "rand")]
+    seed: Option<u64>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            mode: Mode::default(),
+            order: Order::default(),
+            prompt: None,
+            exact: false,
+            init: None,
+            history_size: 1000,
+            time: false,
+            echo: false,
+            result_line: false,
+            result_marker: "RESULT:".to_string(),
+            group: false,
+            group_separator: ',',
+            max_depth: 1_000_000,
+            max_input_length: 1 << 20,
+            denominator_limit: 0,
+            strict_tokens: false,
+            radix: 10,
+            enabled_ops: None,
+            disabled_ops: HashSet::new(),
+            sandbox: false,
+            round_mode: RoundMode::default(),
+            #[cfg(feature = "rand")]
+            seed: None,
+        }
+    }
+}
+
+impl Settings {
+    /// The separator for grouping digits in output, if grouping is on.
+    fn grouping(&self) -> Option<char> {
+        self.group.then_some(self.group_separator)
+    }
+
+    /// Handles `settings`, listing every setting as `name = value`, one per
+    /// line in a fixed order. Names follow the command line options.
+    fn listing(&self) -> String {
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        let ops = |ops: &HashSet<&str>| {
+            let mut ops: Vec<_> = ops.iter().copied().collect();
+            ops.sort();
+            ops.join(" ")
+        };
+        let settings = vec![
+            (
+                "mode",
+                match self.mode {
+                    Mode::Rpn => "rpn",
+                    Mode::Infix => "infix",
+                }
+                .to_string(),
+            ),
+            (
+                "display",
+                match self.order {
+                    Order::BottomFirst => "bottom",
+                    Order::TopFirst => "top",
+                }
+                .to_string(),
+            ),
+            ("exact", on_off(self.exact).to_string()),
+            ("time", on_off(self.time).to_string()),
+            ("echo", on_off(self.echo).to_string()),
+            ("group", on_off(self.group).to_string()),
+            ("group-separator", self.group_separator.to_string()),
+            ("result-line", on_off(self.result_line).to_string()),
+            ("result-marker", self.result_marker.clone()),
+            ("strict-tokens", on_off(self.strict_tokens).to_string()),
+            ("sandbox", on_off(self.sandbox).to_string()),
+            (
+                "prompt",
+                self.prompt.as_deref().unwrap_or(DEFAULT_PROMPT).to_string(),
+            ),
+            (
+                "init",
+                self.init
+                    .as_ref()
+                    .map_or("default".to_string(), |p| p.display().to_string()),
+            ),
+            ("radix", self.radix.to_string()),
+            ("history-size", self.history_size.to_string()),
+            ("max-depth", self.max_depth.to_string()),
+            ("max-input-length", self.max_input_length.to_string()),
+            (
+                "output-denominator-limit",
+                self.denominator_limit.to_string(),
+            ),
+            ("round-mode", self.round_mode.to_string()),
+            (
+                "enable-ops",
+                self.enabled_ops.as_ref().map_or("all".to_string(), ops),
+            ),
+            (
+                "disable-ops",
+                Some(&self.disabled_ops)
+                    .filter(|ops| !ops.is_empty())
+                    .map_or("none".to_string(), ops),
+            ),
+        ];
+        #[cfg(feature = "rand")]
+        let settings: Vec<_> = settings
+            .into_iter()
+            .chain([(
+                "seed",
+                self.seed.map_or("none".to_string(), |s| s.to_string()),
+            )])
+            .collect();
+        settings
+            .into_iter()
+            .map(|(name, value)| format!("{name} = {value}\n"))
+            .collect()
+    }
+
+    /// Whether `--enable-ops` and `--disable-ops` let input use `op`.
+    fn allows(&self, op: &Operator) -> bool {
+        let symbol = op.symbol();
+        self.enabled_ops
+            .as_ref()
+            .is_none_or(|enabled| enabled.contains(symbol))
+            && !self.disabled_ops.contains(symbol)
+    }
+
+    fn from_args<I: IntoIterator<Item = String>>(args: I) -> anyhow::Result<Self> {
+        let mut settings = Settings::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut flag_value = || {
+                args.next()
+                    .ok_or_else(|| anyhow::anyhow!("{arg} needs a value"))
+            };
+            match arg.as_str() {
+                "--infix" => settings.mode = Mode::Infix,
+                "--exact" => settings.exact = true,
+                "--time" => settings.time = true,
+                "--echo" => settings.echo = true,
+                "--group" => settings.group = true,
+                "--result-line" => settings.result_line = true,
+                "--result-marker" => {
+                    settings.result_marker = flag_value()?;
+                    settings.result_line = true
+                }
+                "--group-separator" => {
+                    let sep = flag_value()?;
+                    let mut chars = sep.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) => settings.group_separator = c,
+                        _ => anyhow::bail!("--group-separator needs a single character"),
+                    }
+                    settings.group = true
+                }
+                "--strict-tokens" => settings.strict_tokens = true,
+                "--enable-ops" => settings.enabled_ops = Some(operator_symbols(&flag_value()?)?),
+                "--disable-ops" => settings.disabled_ops = operator_symbols(&flag_value()?)?,
+                "--sandbox" => settings.sandbox = true,
+                "--init" => settings.init = Some(flag_value()?.into()),
+                #[cfg(feature = "rand")]
+                "--seed" => settings.seed = Some(flag_value()?.parse()?),
+                "--prompt" => settings.prompt = Some(flag_value()?),
+                "--history-size" => settings.history_size = flag_value()?.parse()?,
+                "--radix" => settings.radix = parse_radix(&flag_value()?)?,
+                "--max-depth" => settings.max_depth = flag_value()?.parse()?,
+                "--max-input-length" => settings.max_input_length = flag_value()?.parse()?,
+                "--output-denominator-limit" => {
+                    settings.denominator_limit = flag_value()?.parse()?
+                }
+                "--round-mode" => settings.round_mode = flag_value()?.parse()?,
+                _ => anyhow::bail!("Unknown argument: {arg}"),
+            }
+        }
+        if settings.sandbox && settings.init.is_some() {
+            anyhow::bail!("--init is disabled in sandbox mode");
+        }
+        Ok(settings)
+    }
+}
+
+/// A radix for `--radix` or `radix`, which must be from 2 to 36.
+fn parse_radix(n: &str) -> anyhow::Result<u32> {
+    match n.parse() {
+        Ok(radix) if (2..=36).contains(&radix) => Ok(radix),
+        _ => anyhow::bail!("Invalid radix: {n}"),
+    }
+}
+
+/// The symbols in a list of operators such as `"+-*/"` or `"sort, dedup"`,
+/// for `--enable-ops` and `--disable-ops`. `fold` and `map` are named
+/// without a macro.
+fn operator_symbols(list: &str) -> anyhow::Result<HashSet<&'static str>> {
+    let separator = |i| take_while(|c: char| c.is_whitespace() || c == ',')(i);
+    let symbol = alt((
+        map(Operator::parse, |op| op.symbol()),
+        value("fold", keyword("fold")),
+        value("map", keyword("map")),
+    ));
+    match all_consuming(many0(delimited(separator, symbol, separator)))(list) {
+        Ok((_, symbols)) => Ok(symbols.into_iter().collect()),
+        Err(_) => anyhow::bail!("Unknown operator in list: {list}"),
+    }
+}
+
+const USAGE: &str = "\
+Usage: calc-rs [OPTIONS]
+
+An RPN calculator working on exact fractions. Each line is evaluated on top
+of the stack left by the previous one.
+
+Options:
+  --infix               Read input as infix expressions instead of RPN
+  --exact               Refuse results that would need approximating
+  --time                Report how long each evaluation takes
+  --echo                Print each line in canonical RPN form with its result
+  --result-line         After piped input, print the final top of the stack
+                        as \"RESULT: <value>\"
+  --result-marker <STR> Marker for --result-line, implies it
+  --group               Group whole numbers into thousands, as in 1,000,000
+  --group-separator <C> Separator for --group, implies it (default \",\")
+  --strict-tokens       Require whitespace after numbers, so 6-2 is an error
+  --enable-ops <OPS>    Only accept these operators, e.g. \"+-*/\"
+  --disable-ops <OPS>   Reject these operators, e.g. \"sort, dedup\"
+  --sandbox             Disable init files and the clipboard
+  --prompt <STR>        Prompt to show (default \"> \", or $CALC_PROMPT)
+  --init <PATH>         File of commands to run first (default ~/.calcrc)
+  --history-size <N>    Number of lines `history` remembers (default 1000)
+  --radix <N>           Base from 2 to 36 for numbers in input (default 10);
+                        write @c, @ff for operators that read as numbers
+  --max-depth <N>       Maximum number of values on the stack
+  --max-input-length <N>
+                        Longest line to accept, in bytes (default 1 MiB)
+  --output-denominator-limit <N>
+                        Fail when a result's denominator exceeds N (0: off)
+  --round-mode <MODE>   half-up (default), half-even, toward-zero or
+                        away-from-zero, used by `round`, `ond`, `sigfig` and
+                        `pct%`; `divmod` always floors, and results of q, exp
+                        and the like are approximated, not rounded
+  --seed <N>            Seed for `rand` and `randint`, or $CALC_SEED (rand
+                        feature only)
+  -h, --help            Print this help and exit
+  -V, --version         Print the version and exit
+
+Operators:
+  + - * / ^             Arithmetic on the top two values
+  absdiff               a b -> |a - b|
+  ff                    n k -> n (n-1) ... (n-k+1), the falling factorial
+  euclid                a b -> gcd(a, b) steps, counting Euclid's divisions
+  digits                Number of decimal digits of an integer, ignoring sign
+  rdigits               Reverse the decimal digits of an integer: -120 -> -21
+  lastdigit             Last decimal digit of an integer, ignoring sign
+  pal?                  1 if an integer's digits read the same both ways
+  dsum  droot           Digit sum of an integer, repeated down to one digit
+  phi                   Euler's totient of a positive integer
+  nextprime             The smallest prime >= n
+  coprime?              a b -> 1 if gcd(a, b) is 1, else 0
+  modinv                a m -> the x in [0, m) with a x = 1 (mod m)
+  val                   n p -> how many times p divides n
+  popcount              Number of 1 bits in a non-negative integer
+  pow2?                 1 if an integer is 1, 2, 4, 8, ..., else 0
+  tri  pent             n -> n(n+1)/2 or n(3n-1)/2, for n >= 0
+  S                     Sum the whole stack
+  c  p  dup             Clear the stack, pop the top value, copy the top value
+  sorted?               1 if the stack is in non-decreasing order, else 0
+  count                 x -> how many of the values below x equal it
+  maxall  minall        Push the largest or smallest value on the stack
+  sort  sortdesc        Sort the stack so the top is largest, or smallest
+  range                 start end step -> start, start+step, ... up to end
+  dedup                 Collapse runs of equal neighbouring values to one
+  uniq                  Keep only the lowest copy of each value
+  insert                v n -> v placed with n values above it (0 is the top)
+  remove                n -> drop the value with n values above it
+  swapat                i j -> swap the values at depths i and j (0 is the top)
+  find                  x -> depth of the topmost x (0 is the top), or -1
+  rotate                n -> move the bottom n values to the top (n < 0: top
+                        -n values to the bottom)
+  fold <NAME>           Reduce the stack with a two-value macro, bottom first
+  map <NAME>            Replace each value with the result of a one-value macro
+  q  exp  exp10         Square root, e^x, 10^x
+  root                  x n -> the nth root of x
+  between?              x lo hi -> 1 if lo <= x <= hi, else 0
+  approxeq              a b tol -> 1 if |a - b| <= tol, else 0
+  sumsq variance stddev Sum of squares, population variance and deviation
+  hmean  gmean          Harmonic and geometric mean of the whole stack
+  median                Median of the whole stack
+  mode!                 The most common values of the stack, largest on top
+  dot                   Dot product of the bottom and top halves of the stack
+  wsum                  v1 w1 v2 w2 ... -> v1*w1 + v2*w2 + ...
+  prob                  Divide every value by the total, so they sum to 1
+  scaleall              k -> multiply every remaining value by k
+  addall                c -> add c to every remaining value
+  par                   1 / (1/a + 1/b + ...) over the whole stack
+  cummax                Replace each value with the largest at or below it
+  diff                  x0 x1 ... xn -> x1-x0 ... xn-x(n-1), one value fewer
+  polyval               c_n ... c_1 c_0 x -> c_n x^n + ... + c_1 x + c_0
+  polyderiv             Replace the coefficients with those of the derivative
+                        (a constant polynomial becomes 0)
+  geomsum               a r n -> a + a r + ... + a r^(n-1)
+  pv                    fv r n -> fv / (1 + r)^n, the present value
+  mediant               a b -> (numer a + numer b) / (denom a + denom b)
+  approx                x d -> closest fraction to x with denominator <= d
+  floaterr              x -> x minus its nearest f64, as an exact fraction
+  conv                  Replace x with its continued fraction convergents,
+                        ending with x itself
+  .  .s                 Print and drop the top value, print the stack
+  peek                  Print the top value and push a copy of it
+  divmod                a b -> floor(a/b) and the remainder, signed like b
+  round                 Round to an integer using the rounding mode
+  ond                   x d -> x rounded to a whole number of 1/d steps
+  sigfig                x n -> x rounded to n significant figures
+  pct%                  Print the top value as a percentage
+  base                  v b -> v, printing the integer v in base b (2 to 36)
+  rand  randint         Random value in [0, 1), random integer in [lo, hi]
+
+Commands:
+  mode infix|rpn        Switch input syntax
+  display top|bottom    Print the stack top-first or bottom-first
+  exact on|off          Toggle exact mode
+  time on|off           Toggle timing
+  echo on|off           Toggle echoing
+  group on|off          Toggle grouping into thousands
+  maxdepth <N>          Change the maximum stack depth
+  radix <N>             Change the base numbers are read in
+  roundmode <MODE>      Change the rounding mode
+  settings              List every setting as `name = value`
+  def <NAME> <BODY>     Define a macro
+  defs  undef <NAME>    List macros, forget one
+  store <NAME>          Save the top value; NAME pushes it again
+  vars  unset <NAME>    List variables, forget one
+  assert <VALUES>       Check the stack holds exactly VALUES
+  dump                  List the stack with depths, top first
+  copy                  Copy the top value to the clipboard (clipboard feature)
+  history  !<N>         List previous lines, run line N again
+  reset                 Clear the stack, macros and history
+  exit                  Quit
+
+Examples:
+  3 6 + 2 *             => 18
+  1 2 / 1 3 / +         => 5/6
+  def sq 2 ^            then: 7 sq => 49
+";
+
+const DEFAULT_PROMPT: &str = "> ";
+
+/// Picks the REPL prompt: an explicit `--prompt` wins over the `CALC_PROMPT`
+/// environment variable, which wins over the default `> `. An empty string
+/// is a valid prompt, which is handy when piping.
+fn resolve_prompt(flag: Option<String>, env: Option<String>) -> String {
+    flag.or(env).unwrap_or_else(|| DEFAULT_PROMPT.to_string())
+}
+
+/// Picks the seed for `rand` and `randint`: an explicit `--seed` wins over
+/// the `CALC_SEED` environment variable. With neither, there is no seed and
+/// the generator is seeded from the OS instead.
+#[cfg(feature = "rand")]
+fn resolve_seed(flag: Option<u64>, env: Option<String>) -> anyhow::Result<Option<u64>> {
+    match (flag, env) {
+        (Some(seed), _) => Ok(Some(seed)),
+        (None, Some(env)) => env
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| anyhow::anyhow!("Invalid CALC_SEED: {env}")),
+        (None, None) => Ok(None),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 enum Item {
     Num(Rational64),
     Operator(Operator),
+    /// A name that is not an operator, looked up among the defined macros.
+    Word(String),
 }
 impl Item {
+    /// Numbers are tried before operators, so a sign glued to digits is part
+    /// of the literal (`-5`, `+5`), while a sign followed by whitespace,
+    /// another operator or the end of input is the operator itself. This
+    /// means `3 -5` and `3-5` both push `3` and `-5`, whereas `3 - 5` pushes
+    /// `3` and `5` and subtracts.
+    ///
+    /// Operators spelled with letters only match as whole words, so anything
+    /// else made of letters, digits and underscores is a `Word`.
+    ///
+    /// With `strict_tokens` set, a number must be followed by whitespace or
+    /// the end of the line, so the ambiguous `6-2` is rejected while `6 - 2`
+    /// and `6 -2` are still accepted.
+    ///
+    /// In a `radix` other than 10, a whole word made only of digits in that
+    /// radix is a number, so under 16 `ff` is 255 and `c` is 12. Operators
+    /// can always be written with a leading `@`, so `@c` still clears and
+    /// `@ff` is the falling factorial. Any other word starting with a digit
+    /// of the radix, such as `fg`, is a malformed number rather than a word.
+    #[cfg(test)]
     fn parse(i: &str) -> IResult<&str, Self> {
+        Item::parse_with(i, &Settings::default())
+    }
+
+    fn parse_with<'a>(i: &'a str, settings: &Settings) -> IResult<&'a str, Self> {
         alt((
-            map(cc::i64, |i| Item::Num(Rational64::from(i))),
-            map(Operator::parse, Item::Operator),
+            map(|i| number(i, settings), Item::Num),
+            map(
+                alt((preceded(cc::char('@'), |i| operator(i, settings)), |i| {
+                    operator(i, settings)
+                })),
+                Item::Operator,
+            ),
+            map(|i| word(i, settings), |w: &str| Item::Word(w.to_string())),
         ))(i)
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+/// A macro or variable name. In a `radix` other than 10 one that starts with
+/// a digit of the radix could only have been meant as a number, so it is a
+/// hard failure instead.
+fn word<'a>(i: &'a str, settings: &Settings) -> IResult<&'a str, &'a str> {
+    let (rest, w) = take_while1(is_word_char)(i)?;
+    if settings.radix != 10 && w.starts_with(|c: char| c.is_digit(settings.radix)) {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            i,
+            nom::error::ErrorKind::Digit,
+        )));
+    }
+    Ok((rest, w))
+}
+
+fn number<'a>(i: &'a str, settings: &Settings) -> IResult<&'a str, Rational64> {
+    let (rest, n) = match settings.radix {
+        10 => cc::i64(i)?,
+        radix => radix_integer(i, radix)?,
+    };
+    if settings.strict_tokens && rest.starts_with(|c: char| !c.is_whitespace()) {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            rest,
+            nom::error::ErrorKind::Space,
+        )));
+    }
+    Ok((rest, Rational64::from(n)))
+}
+
+/// An operator that `settings` allows. A disabled one is a hard failure,
+/// rather than being left unparsed or read as a word.
+fn operator<'a>(i: &'a str, settings: &Settings) -> IResult<&'a str, Operator> {
+    let (rest, op) = Operator::parse(i)?;
+    if !settings.allows(&op) {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            i,
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    Ok((rest, op))
+}
+
+/// An integer written in `radix`, with an optional sign, that makes up a
+/// whole word.
+fn radix_integer(i: &str, radix: u32) -> IResult<&str, i64> {
+    let (rest, digits) = terminated(
+        recognize(pair(
+            opt(cc::one_of("+-")),
+            take_while1(|c: char| c.is_digit(radix)),
+        )),
+        not(satisfy(is_word_char)),
+    )(i)?;
+    let n = i64::from_str_radix(digits, radix)
+        .map_err(|_| nom::Err::Error(nom::error::Error::new(i, nom::error::ErrorKind::Digit)))?;
+    Ok((rest, n))
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Matches `word` only when it isn't immediately followed by more of a word,
+/// so that e.g. `c` doesn't match the start of `cube`.
+fn keyword<'a>(word: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    terminated(tag(word), not(satisfy(is_word_char)))
+}
+
+#[derive(Debug, PartialEq, Clone)]
 enum Operator {
     Add,
     Multiply,
     Subtract,
     Divide,
+    AbsDiff,
+    FallingFactorial,
+    Euclid,
+    Digits,
+    ReverseDigits,
+    LastDigit,
+    IsPalindrome,
+    DigitSum,
+    DigitalRoot,
+    Totient,
+    NextPrime,
+    Coprime,
+    ModInv,
+    Valuation,
+    PopCount,
+    IsPowerOfTwo,
+    Triangular,
+    Pentagonal,
     Sum,
     Power,
     Clear,
     Pop,
+    Dup,
+    IsSorted,
+    Count,
+    StackMax,
+    StackMin,
+    Sort,
+    SortDesc,
+    Dedup,
+    Unique,
+    InsertAt,
+    RemoveAt,
+    SwapAt,
+    Find,
+    RotateStack,
+    Range,
+    Fold(MacroRef),
+    Map(MacroRef),
+    Exp,
+    Exp10,
+    Between,
+    ApproxEq,
+    Sqrt,
+    Root,
+    SumSq,
+    Variance,
+    StdDev,
+    HarmonicMean,
+    GeoMean,
+    Median,
+    Mode,
+    Dot,
+    WeightedSum,
+    NormalizeSum,
+    ScaleAll,
+    OffsetAll,
+    ParallelSum,
+    CumMax,
+    Diff,
+    PolyVal,
+    PolyDeriv,
+    GeomSum,
+    PresentValue,
+    Mediant,
+    Approx,
+    FloatError,
+    Convergents,
+    Emit,
+    PrintStack,
+    Peek,
+    DivMod,
+    Round,
+    OnDenominator,
+    SigFig,
+    ShowPercent,
+    ShowBase,
+    #[cfg(feature = "rand")]
+    Rand,
+    #[cfg(feature = "rand")]
+    RandInt,
 }
 
 impl Operator {
     fn parse(i: &str) -> IResult<&str, Self> {
+        alt((
+            Operator::parse_arithmetic,
+            Operator::parse_stack,
+            Operator::parse_statistics,
+            Operator::parse_polynomial,
+            Operator::parse_digits,
+            Operator::parse_rational,
+            Operator::parse_output,
+            Operator::parse_rand,
+        ))(i)
+    }
+
+    fn parse_arithmetic(i: &str) -> IResult<&str, Self> {
         alt((
             value(Operator::Add, tag("+")),
             value(Operator::Multiply, tag("*")),
             value(Operator::Subtract, tag("-")),
-            value(Operator::Sum, tag("S")),
+            value(Operator::Sum, keyword("S")),
             value(Operator::Power, tag("^")),
-            value(Operator::Clear, tag("c")),
             value(Operator::Divide, tag("/")),
-            value(Operator::Pop, tag("p")),
+            value(Operator::AbsDiff, keyword("absdiff")),
+            value(Operator::FallingFactorial, keyword("ff")),
+            value(Operator::Euclid, keyword("euclid")),
+            value(Operator::Exp10, keyword("exp10")),
+            value(Operator::Exp, keyword("exp")),
+            value(Operator::Sqrt, keyword("q")),
+            value(Operator::Root, keyword("root")),
+            value(Operator::Mediant, keyword("mediant")),
+            value(Operator::Approx, keyword("approx")),
+            value(Operator::DivMod, keyword("divmod")),
+            value(Operator::Round, keyword("round")),
+            value(Operator::OnDenominator, keyword("ond")),
+            value(Operator::SigFig, keyword("sigfig")),
+            value(Operator::Between, keyword("between?")),
+            value(Operator::ApproxEq, keyword("approxeq")),
+        ))(i)
+    }
+
+    fn parse_stack(i: &str) -> IResult<&str, Self> {
+        alt((
+            value(Operator::Clear, keyword("c")),
+            value(Operator::Pop, keyword("p")),
+            value(Operator::Dup, keyword("dup")),
+            value(Operator::IsSorted, keyword("sorted?")),
+            value(Operator::Count, keyword("count")),
+            value(Operator::StackMax, keyword("maxall")),
+            value(Operator::StackMin, keyword("minall")),
+            value(Operator::Sort, keyword("sort")),
+            value(Operator::SortDesc, keyword("sortdesc")),
+            value(Operator::Dedup, keyword("dedup")),
+            value(Operator::Unique, keyword("uniq")),
+            value(Operator::InsertAt, keyword("insert")),
+            value(Operator::RemoveAt, keyword("remove")),
+            value(Operator::SwapAt, keyword("swapat")),
+            value(Operator::Find, keyword("find")),
+            value(Operator::RotateStack, keyword("rotate")),
+            value(Operator::Range, keyword("range")),
+            map(
+                preceded(pair(keyword("fold"), cc::multispace1), MacroRef::parse),
+                Operator::Fold,
+            ),
+            map(
+                preceded(pair(keyword("map"), cc::multispace1), MacroRef::parse),
+                Operator::Map,
+            ),
+        ))(i)
+    }
+
+    fn parse_statistics(i: &str) -> IResult<&str, Self> {
+        alt((
+            value(Operator::SumSq, keyword("sumsq")),
+            value(Operator::Variance, keyword("variance")),
+            value(Operator::StdDev, keyword("stddev")),
+            value(Operator::HarmonicMean, keyword("hmean")),
+            value(Operator::GeoMean, keyword("gmean")),
+            value(Operator::Median, keyword("median")),
+            value(Operator::Mode, keyword("mode!")),
+            value(Operator::Dot, keyword("dot")),
+            value(Operator::WeightedSum, keyword("wsum")),
+            value(Operator::NormalizeSum, keyword("prob")),
+            value(Operator::ScaleAll, keyword("scaleall")),
+            value(Operator::OffsetAll, keyword("addall")),
+            value(Operator::ParallelSum, keyword("par")),
+            value(Operator::CumMax, keyword("cummax")),
+            value(Operator::Diff, keyword("diff")),
+        ))(i)
+    }
+
+    fn parse_polynomial(i: &str) -> IResult<&str, Self> {
+        alt((
+            value(Operator::PolyVal, keyword("polyval")),
+            value(Operator::PolyDeriv, keyword("polyderiv")),
+            value(Operator::GeomSum, keyword("geomsum")),
+            value(Operator::PresentValue, keyword("pv")),
+        ))(i)
+    }
+
+    fn parse_digits(i: &str) -> IResult<&str, Self> {
+        alt((
+            value(Operator::Digits, keyword("digits")),
+            value(Operator::ReverseDigits, keyword("rdigits")),
+            value(Operator::LastDigit, keyword("lastdigit")),
+            value(Operator::IsPalindrome, keyword("pal?")),
+            value(Operator::DigitSum, keyword("dsum")),
+            value(Operator::DigitalRoot, keyword("droot")),
+            value(Operator::Totient, keyword("phi")),
+            value(Operator::NextPrime, keyword("nextprime")),
+            value(Operator::Coprime, keyword("coprime?")),
+            value(Operator::ModInv, keyword("modinv")),
+            value(Operator::Valuation, keyword("val")),
+            value(Operator::PopCount, keyword("popcount")),
+            value(Operator::IsPowerOfTwo, keyword("pow2?")),
+            value(Operator::Triangular, keyword("tri")),
+            value(Operator::Pentagonal, keyword("pent")),
+        ))(i)
+    }
+
+    fn parse_rational(i: &str) -> IResult<&str, Self> {
+        alt((
+            value(Operator::FloatError, keyword("floaterr")),
+            value(Operator::Convergents, keyword("conv")),
+        ))(i)
+    }
+
+    fn parse_output(i: &str) -> IResult<&str, Self> {
+        alt((
+            value(Operator::PrintStack, keyword(".s")),
+            value(Operator::Emit, tag(".")),
+            value(Operator::Peek, keyword("peek")),
+            value(Operator::ShowPercent, keyword("pct%")),
+            value(Operator::ShowBase, keyword("base")),
+        ))(i)
+    }
+
+    #[cfg(feature = "rand")]
+    fn parse_rand(i: &str) -> IResult<&str, Self> {
+        alt((
+            value(Operator::Rand, keyword("rand")),
+            value(Operator::RandInt, keyword("randint")),
         ))(i)
     }
+
+    /// Without the `rand` feature, `rand` is just an unknown word.
+    #[cfg(not(feature = "rand"))]
+    fn parse_rand(i: &str) -> IResult<&str, Self> {
+        nom::combinator::fail(i)
+    }
+
+    /// How the operator is written in RPN input.
+    fn symbol(&self) -> &'static str {
+        match self {
+            Operator::Add => "+",
+            Operator::Multiply => "*",
+            Operator::Subtract => "-",
+            Operator::Divide => "/",
+            Operator::AbsDiff => "absdiff",
+            Operator::FallingFactorial => "ff",
+            Operator::Euclid => "euclid",
+            Operator::Digits => "digits",
+            Operator::ReverseDigits => "rdigits",
+            Operator::LastDigit => "lastdigit",
+            Operator::IsPalindrome => "pal?",
+            Operator::DigitSum => "dsum",
+            Operator::DigitalRoot => "droot",
+            Operator::Totient => "phi",
+            Operator::NextPrime => "nextprime",
+            Operator::Coprime => "coprime?",
+            Operator::ModInv => "modinv",
+            Operator::Valuation => "val",
+            Operator::PopCount => "popcount",
+            Operator::IsPowerOfTwo => "pow2?",
+            Operator::Triangular => "tri",
+            Operator::Pentagonal => "pent",
+            Operator::Sum => "S",
+            Operator::Power => "^",
+            Operator::Clear => "c",
+            Operator::Pop => "p",
+            Operator::Dup => "dup",
+            Operator::IsSorted => "sorted?",
+            Operator::Count => "count",
+            Operator::StackMax => "maxall",
+            Operator::StackMin => "minall",
+            Operator::Sort => "sort",
+            Operator::SortDesc => "sortdesc",
+            Operator::Dedup => "dedup",
+            Operator::Unique => "uniq",
+            Operator::InsertAt => "insert",
+            Operator::RemoveAt => "remove",
+            Operator::SwapAt => "swapat",
+            Operator::Find => "find",
+            Operator::RotateStack => "rotate",
+            Operator::Range => "range",
+            Operator::Fold(_) => "fold",
+            Operator::Map(_) => "map",
+            Operator::Exp => "exp",
+            Operator::Exp10 => "exp10",
+            Operator::Between => "between?",
+            Operator::ApproxEq => "approxeq",
+            Operator::Sqrt => "q",
+            Operator::Root => "root",
+            Operator::SumSq => "sumsq",
+            Operator::Variance => "variance",
+            Operator::StdDev => "stddev",
+            Operator::HarmonicMean => "hmean",
+            Operator::GeoMean => "gmean",
+            Operator::Median => "median",
+            Operator::Mode => "mode!",
+            Operator::Dot => "dot",
+            Operator::WeightedSum => "wsum",
+            Operator::NormalizeSum => "prob",
+            Operator::ScaleAll => "scaleall",
+            Operator::OffsetAll => "addall",
+            Operator::ParallelSum => "par",
+            Operator::CumMax => "cummax",
+            Operator::Diff => "diff",
+            Operator::PolyVal => "polyval",
+            Operator::PolyDeriv => "polyderiv",
+            Operator::GeomSum => "geomsum",
+            Operator::PresentValue => "pv",
+            Operator::Mediant => "mediant",
+            Operator::Approx => "approx",
+            Operator::FloatError => "floaterr",
+            Operator::Convergents => "conv",
+            Operator::Emit => ".",
+            Operator::PrintStack => ".s",
+            Operator::Peek => "peek",
+            Operator::DivMod => "divmod",
+            Operator::Round => "round",
+            Operator::OnDenominator => "ond",
+            Operator::SigFig => "sigfig",
+            Operator::ShowPercent => "pct%",
+            Operator::ShowBase => "base",
+            #[cfg(feature = "rand")]
+            Operator::Rand => "rand",
+            #[cfg(feature = "rand")]
+            Operator::RandInt => "randint",
+        }
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operator::Fold(m) | Operator::Map(m) => write!(f, "{} {}", self.symbol(), m.name),
+            _ => write!(f, "{}", self.symbol()),
+        }
+    }
+}
+
+/// A macro named as the argument of an operator such as `fold` or `map`. Parsing
+/// only records the name; `Line::expand` fills in the body, so, as with
+/// macros used directly, redefining it later does not change lines that were
+/// already expanded.
+#[derive(Debug, PartialEq, Clone)]
+struct MacroRef {
+    name: String,
+    body: Option<Line>,
+}
+
+impl MacroRef {
+    fn parse(i: &str) -> IResult<&str, Self> {
+        map(take_while1(is_word_char), |name: &str| MacroRef {
+            name: name.to_string(),
+            body: None,
+        })(i)
+    }
+
+    /// The same reference with the body of the macro filled in.
+    fn resolve(&self, macros: &HashMap<String, Line>) -> Result<Self, CalcError> {
+        let body = macros
+            .get(&self.name)
+            .ok_or_else(|| CalcError::UnknownWord(self.name.clone()))?;
+        Ok(MacroRef {
+            name: self.name.clone(),
+            body: Some(body.clone()),
+        })
+    }
+
+    /// Runs the macro on its own stack holding just `args`, failing unless
+    /// it leaves exactly `results` values.
+    fn apply(
+        &self,
+        args: Vec<Rational64>,
+        results: usize,
+        calculator: &mut Calculator,
+    ) -> Result<Vec<Rational64>, CalcError> {
+        let body = self
+            .body
+            .as_ref()
+            .ok_or_else(|| CalcError::UnknownWord(self.name.clone()))?;
+        let stack = body.calc_with(Stack(args), calculator)?;
+        if stack.0.len() != results {
+            return Err(CalcError::MacroArity(self.name.clone()));
+        }
+        Ok(stack.0)
+    }
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Item::Num(n) => write!(f, "{n}"),
+            Item::Operator(op) => write!(f, "{op}"),
+            Item::Word(w) => write!(f, "{w}"),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Default)]
+#[derive(Debug, PartialEq, Default, Clone)]
 struct Line(Vec<Item>);
 
 impl Line {
+    #[cfg(test)]
     fn parse(i: &str) -> IResult<&str, Self> {
+        Line::parse_with(i, &Settings::default())
+    }
+
+    fn parse_with<'a>(i: &'a str, settings: &Settings) -> IResult<&'a str, Self> {
         map(
-            many0(delimited(cc::multispace0, Item::parse, cc::multispace0)),
+            many0(delimited(
+                cc::multispace0,
+                |i| Item::parse_with(i, settings),
+                cc::multispace0,
+            )),
             Line,
         )(i)
     }
 
+    /// Parses an infix expression such as `(3 + 4) * 2` into the equivalent
+    /// postfix line, so it can be evaluated by `calc` like any other input.
+    ///
+    /// `*` and `/` bind tighter than `+` and `-`, `^` binds tightest (even
+    /// over a leading `-`, so `-2^2` is -4) and is right associative, and
+    /// parentheses group as usual.
+    fn parse_infix(i: &str) -> IResult<&str, Self> {
+        map(
+            all_consuming(delimited(cc::multispace0, infix_sum, cc::multispace0)),
+            Line,
+        )(i)
+    }
+
+    /// Replaces every macro name in the line with the macro's body, and every
+    /// variable name with the variable's current value. Macros win if a name
+    /// is both.
+    fn expand(
+        &self,
+        macros: &HashMap<String, Line>,
+        variables: &HashMap<String, Rational64>,
+    ) -> Result<Line, CalcError> {
+        let mut items = Vec::new();
+        for item in &self.0 {
+            match item {
+                Item::Word(name) => match (macros.get(name), variables.get(name)) {
+                    (Some(body), _) => items.extend(body.0.iter().cloned()),
+                    (None, Some(value)) => items.push(Item::Num(*value)),
+                    (None, None) => return Err(CalcError::UnknownWord(name.clone())),
+                },
+                Item::Operator(Operator::Fold(m)) => {
+                    items.push(Item::Operator(Operator::Fold(m.resolve(macros)?)))
+                }
+                Item::Operator(Operator::Map(m)) => {
+                    items.push(Item::Operator(Operator::Map(m.resolve(macros)?)))
+                }
+                item => items.push(item.clone()),
+            }
+        }
+        Ok(Line(items))
+    }
+
+    /// Evaluates the line in a fresh session with the default settings.
+    #[cfg(test)]
     fn calc(&self, existing_stack: Stack) -> Result<Stack, CalcError> {
-        let result = self.0.iter().fold(Ok(existing_stack), |stack, item| {
-            let mut stack = stack?;
+        self.calc_with(existing_stack, &mut Calculator::default())
+    }
+
+    /// Evaluates the line on top of `existing_stack`, using the settings and
+    /// state of `calculator` (but not its stack).
+    fn calc_with(
+        &self,
+        existing_stack: Stack,
+        calculator: &mut Calculator,
+    ) -> Result<Stack, CalcError> {
+        self.0.iter().try_fold(existing_stack, |mut stack, item| {
             match item {
                 Item::Num(number) => stack.0.push(*number),
+                Item::Word(name) => return Err(CalcError::UnknownWord(name.clone())),
                 Item::Operator(op) => match op {
                     Operator::Add => {
                         let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
@@ -128,327 +1585,5035 @@ impl Line {
                         let b = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
                         stack.0.push(b.checked_sub(&a).ok_or(CalcError::MathError)?)
                     }
-                    Operator::Divide => {
+                    Operator::AbsDiff => {
                         let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
                         let b = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
-                        stack.0.push(b.checked_div(&a).ok_or(CalcError::MathError)?)
+                        let (lo, hi) = if a < b { (a, b) } else { (b, a) };
+                        stack
+                            .0
+                            .push(hi.checked_sub(&lo).ok_or(CalcError::Overflow)?)
+                    }
+                    Operator::FallingFactorial => {
+                        let k = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let n = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let (n, k) = (integer(&n)?, integer(&k)?);
+                        if n < 0 || k < 0 {
+                            return Err(CalcError::MathError);
+                        }
+                        // Once k passes n a factor of zero makes the rest
+                        // irrelevant.
+                        let product = (0..k.min(n.saturating_add(1)))
+                            .try_fold(1i64, |acc, i| acc.checked_mul(n - i));
+                        stack
+                            .0
+                            .push(Rational64::from(product.ok_or(CalcError::Overflow)?))
+                    }
+                    Operator::Euclid => {
+                        let b = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let (gcd, steps) = euclid(integer(&a)?, integer(&b)?)?;
+                        stack.0.push(Rational64::from(gcd));
+                        stack.0.push(Rational64::from(steps));
+                    }
+                    Operator::Digits => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let digits = integer(&a)?.unsigned_abs().checked_ilog10().unwrap_or(0) + 1;
+                        stack.0.push(Rational64::from(digits as i64))
+                    }
+                    Operator::ReverseDigits => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let a = integer(&a)?;
+                        let reversed = decimal_digits(a)
+                            .into_iter()
+                            .try_fold(0i64, |acc, d| acc.checked_mul(10)?.checked_add(d as i64))
+                            .ok_or(CalcError::Overflow)?;
+                        stack.0.push(Rational64::from(reversed * a.signum()))
+                    }
+                    Operator::DigitSum => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        stack.0.push(Rational64::from(digit_sum(integer(&a)?)))
+                    }
+                    Operator::DigitalRoot => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let mut root = digit_sum(integer(&a)?);
+                        while root >= 10 {
+                            root = digit_sum(root)
+                        }
+                        stack.0.push(Rational64::from(root))
+                    }
+                    Operator::Totient => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let n = integer(&a)?;
+                        if n <= 0 {
+                            return Err(CalcError::MathError);
+                        }
+                        // n times (1 - 1/p) for each distinct prime p, which
+                        // never leaves the integers or grows past n.
+                        let phi = prime_factors(n as u64)
+                            .into_iter()
+                            .fold(n, |phi, (p, _)| phi / p as i64 * (p as i64 - 1));
+                        stack.0.push(Rational64::from(phi))
+                    }
+                    Operator::NextPrime => {
+                        // Inclusive, so a prime is its own next prime.
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let mut n = integer(&a)?.max(2);
+                        while !is_prime(n as u64) {
+                            n = n.checked_add(1).ok_or(CalcError::Overflow)?;
+                        }
+                        stack.0.push(Rational64::from(n))
+                    }
+                    Operator::Coprime => {
+                        let b = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        // gcd(i64::MIN, 0) overflows, but isn't 1 either.
+                        let coprime = euclid(integer(&a)?, integer(&b)?).is_ok_and(|(g, _)| g == 1);
+                        stack.0.push(Rational64::from(coprime as i64))
+                    }
+                    Operator::ModInv => {
+                        let m = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let (a, m) = (integer(&a)?, integer(&m)?);
+                        if a <= 0 || m <= 0 {
+                            return Err(CalcError::MathError);
+                        }
+                        stack.0.push(Rational64::from(mod_inverse(a, m)?))
+                    }
+                    Operator::Valuation => {
+                        let p = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let n = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let (mut n, p) = (integer(&n)?, integer(&p)?);
+                        if p < 2 || n == 0 {
+                            return Err(CalcError::MathError);
+                        }
+                        let mut count = 0;
+                        while n % p == 0 {
+                            n /= p;
+                            count += 1;
+                        }
+                        stack.0.push(Rational64::from(count))
+                    }
+                    Operator::PopCount => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let a = integer(&a)?;
+                        if a < 0 {
+                            return Err(CalcError::MathError);
+                        }
+                        stack.0.push(Rational64::from(a.count_ones() as i64))
+                    }
+                    Operator::LastDigit => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let last = decimal_digits(integer(&a)?)[0];
+                        stack.0.push(Rational64::from(last as i64))
+                    }
+                    Operator::IsPowerOfTwo => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let n = integer(&a)?;
+                        let power = n > 0 && n & (n - 1) == 0;
+                        stack.0.push(Rational64::from(power as i64))
+                    }
+                    Operator::Triangular | Operator::Pentagonal => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let n = integer(&a)?;
+                        if n < 0 {
+                            return Err(CalcError::MathError);
+                        }
+                        let m = if *op == Operator::Triangular {
+                            n.checked_add(1)
+                        } else {
+                            n.checked_mul(3).map(|m| m - 1)
+                        };
+                        let figurate = m.and_then(|m| half_product(n, m));
+                        stack
+                            .0
+                            .push(Rational64::from(figurate.ok_or(CalcError::Overflow)?))
                     }
-                    Operator::Sum => {
-                        let s = stack.0.iter().sum();
-                        stack = Stack(vec![s])
+                    Operator::IsPalindrome => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let digits = decimal_digits(integer(&a)?);
+                        let palindrome = digits.iter().eq(digits.iter().rev());
+                        stack.0.push(Rational64::from(palindrome as i64))
+                    }
+                    Operator::Divide => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let b = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        stack.0.push(b.checked_div(&a).ok_or(CalcError::MathError)?)
                     }
+                    Operator::Sum => stack = Stack(vec![checked_sum(&stack.0)?]),
                     Operator::Power => {
                         let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
                         let b = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
-                        stack.0.push(
-                            b.pow(
-                                a.to_integer()
-                                    .try_into()
-                                    .map_err(|_| CalcError::MathError)?,
-                            ),
-                        )
+                        // Fractional exponents would need roots, so refuse
+                        // them rather than silently truncating.
+                        stack.0.push(checked_pow(&b, integer(&a)?)?)
+                    }
+                    Operator::Exp => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        stack.0.push(if a == Rational64::from(0) {
+                            Rational64::from(1)
+                        } else {
+                            approximate(to_float(&a).exp(), &calculator.settings)?
+                        })
+                    }
+                    Operator::Exp10 => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let exact = a
+                            .to_integer()
+                            .try_into()
+                            .ok()
+                            .filter(|_| a.is_integer())
+                            .and_then(|n| 10i64.checked_pow(n));
+                        stack.0.push(match exact {
+                            Some(n) => Rational64::from(n),
+                            None => approximate(10f64.powf(to_float(&a)), &calculator.settings)?,
+                        })
+                    }
+                    Operator::Sqrt => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        stack.0.push(sqrt(&a, &calculator.settings)?)
+                    }
+                    Operator::Root => {
+                        let n = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let x = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let n = u32::try_from(integer(&n)?)
+                            .ok()
+                            .filter(|n| *n > 0)
+                            .ok_or(CalcError::MathError)?;
+                        stack.0.push(nth_root(&x, n, &calculator.settings)?)
+                    }
+                    Operator::Between => {
+                        let hi = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let lo = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let x = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        if lo > hi {
+                            return Err(CalcError::MathError);
+                        }
+                        stack
+                            .0
+                            .push(Rational64::from(i64::from(lo <= x && x <= hi)))
+                    }
+                    Operator::ApproxEq => {
+                        let tol = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let b = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        if tol.is_negative() {
+                            return Err(CalcError::MathError);
+                        }
+                        let (lo, hi) = if a < b { (a, b) } else { (b, a) };
+                        let diff = hi.checked_sub(&lo).ok_or(CalcError::Overflow)?;
+                        stack.0.push(Rational64::from(i64::from(diff <= tol)))
+                    }
+                    #[cfg(feature = "rand")]
+                    Operator::Rand => stack.0.push(calculator.rng.next_rational()),
+                    #[cfg(feature = "rand")]
+                    Operator::RandInt => {
+                        let hi = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let lo = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let (lo, hi) = (integer(&lo)?, integer(&hi)?);
+                        if lo > hi {
+                            return Err(CalcError::MathError);
+                        }
+                        stack
+                            .0
+                            .push(Rational64::from(calculator.rng.next_in_range(lo, hi)))
+                    }
+                    Operator::SumSq => {
+                        let s = stack.0.iter().try_fold(Rational64::from(0), |acc, x| {
+                            x.checked_mul(x).and_then(|sq| acc.checked_add(&sq))
+                        });
+                        stack = Stack(vec![s.ok_or(CalcError::Overflow)?])
+                    }
+                    Operator::Variance => stack = Stack(vec![variance(&stack.0)?]),
+                    Operator::StdDev => {
+                        stack = Stack(vec![sqrt(&variance(&stack.0)?, &calculator.settings)?])
+                    }
+                    Operator::ParallelSum => stack = Stack(vec![parallel_sum(&stack.0)?]),
+                    Operator::HarmonicMean => stack = Stack(vec![harmonic_mean(&stack.0)?]),
+                    Operator::GeoMean => {
+                        stack = Stack(vec![geometric_mean(&stack.0, &calculator.settings)?])
+                    }
+                    Operator::Median => stack = Stack(vec![median(&stack.0)?]),
+                    Operator::Mode => stack = Stack(modes(&stack.0)?),
+                    Operator::Dot => stack = Stack(vec![dot(&stack.0)?]),
+                    Operator::WeightedSum => stack = Stack(vec![weighted_sum(&stack.0)?]),
+                    Operator::PolyVal => {
+                        let x = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        stack = Stack(vec![poly_val(&stack.0, &x)?])
+                    }
+                    Operator::ScaleAll => {
+                        let k = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        for x in &mut stack.0 {
+                            *x = x.checked_mul(&k).ok_or(CalcError::Overflow)?;
+                        }
+                    }
+                    Operator::OffsetAll => {
+                        let c = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        for x in &mut stack.0 {
+                            *x = x.checked_add(&c).ok_or(CalcError::Overflow)?;
+                        }
+                    }
+                    Operator::CumMax => {
+                        for i in 1..stack.0.len() {
+                            stack.0[i] = stack.0[i].max(stack.0[i - 1])
+                        }
+                    }
+                    Operator::Diff => {
+                        // n values leave n - 1 differences, so a single value
+                        // (or none) leaves the stack empty.
+                        let diffs = stack
+                            .0
+                            .windows(2)
+                            .map(|w| w[1].checked_sub(&w[0]).ok_or(CalcError::Overflow))
+                            .collect::<Result<_, _>>()?;
+                        stack = Stack(diffs)
+                    }
+                    Operator::GeomSum => {
+                        let n = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let r = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        stack.0.push(geometric_sum(&a, &r, integer(&n)?)?)
+                    }
+                    Operator::PresentValue => {
+                        let n = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let r = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let fv = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let n = integer(&n)?;
+                        if n < 0 || r == Rational64::from(-1) {
+                            return Err(CalcError::MathError);
+                        }
+                        let growth = r
+                            .checked_add(&Rational64::from(1))
+                            .ok_or(CalcError::Overflow)?;
+                        let growth = checked_pow(&growth, n)?;
+                        stack
+                            .0
+                            .push(fv.checked_div(&growth).ok_or(CalcError::Overflow)?)
+                    }
+                    Operator::PolyDeriv => stack = Stack(poly_deriv(&stack.0)?),
+                    Operator::NormalizeSum => {
+                        if !stack.0.is_empty() {
+                            let total = checked_sum(&stack.0)?;
+                            if total == Rational64::from(0) {
+                                return Err(CalcError::DivideByZero);
+                            }
+                            for x in &mut stack.0 {
+                                *x = x.checked_div(&total).ok_or(CalcError::Overflow)?;
+                            }
+                        }
+                    }
+                    Operator::Mediant => {
+                        // Works on the stored, fully reduced numerators and
+                        // denominators, so 2/4 behaves exactly like 1/2.
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let b = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let numer = b.numer().checked_add(a.numer());
+                        let denom = b.denom().checked_add(a.denom());
+                        match numer.zip(denom) {
+                            Some((numer, denom)) => stack.0.push(Rational64::new(numer, denom)),
+                            None => return Err(CalcError::Overflow),
+                        }
+                    }
+                    Operator::Approx => {
+                        let d = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let x = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let d = integer(&d)?;
+                        if d < 1 {
+                            return Err(CalcError::MathError);
+                        }
+                        stack.0.push(limit_denominator(&x, d)?)
+                    }
+                    Operator::FloatError => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let float = to_float(&a);
+                        let round_trip =
+                            exact_float(float).map_or_else(|| from_float(float), Ok)?;
+                        stack
+                            .0
+                            .push(a.checked_sub(&round_trip).ok_or(CalcError::Overflow)?)
+                    }
+                    Operator::Convergents => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let convergents = convergents(&a)?;
+                        if stack.0.len() + convergents.len() > calculator.settings.max_depth {
+                            return Err(CalcError::StackOverflow);
+                        }
+                        stack.0.extend(convergents)
+                    }
+                    Operator::Emit => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        calculator.emitted.push(a.to_string())
+                    }
+                    Operator::Peek => {
+                        let a = *stack.last().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        calculator.emitted.push(a.to_string());
+                        stack.0.push(a)
+                    }
+                    Operator::PrintStack => calculator.emitted.push(
+                        stack
+                            .display(calculator.settings.order)
+                            .grouped(calculator.settings.grouping())
+                            .to_string(),
+                    ),
+                    Operator::DivMod => {
+                        // Floored division: the quotient rounds towards
+                        // negative infinity, so a non-zero remainder always
+                        // has the same sign as the divisor. This is part of
+                        // what divmod means, so the rounding mode doesn't
+                        // change it.
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let b = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        if a == Rational64::from(0) {
+                            return Err(CalcError::DivideByZero);
+                        }
+                        // `Ratio::floor` and `checked_div` can both
+                        // overflow near the limits, so split the exact
+                        // quotient into whole and fractional parts by hand.
+                        // The remainder is `a` times the fractional part,
+                        // as `b - q a` can overflow even when it fits.
+                        let exact = b.checked_mul(&reciprocal(&a)?).ok_or(CalcError::Overflow)?;
+                        let (n, d) = (*exact.numer(), *exact.denom());
+                        let r = a
+                            .checked_mul(&Rational64::new(n.rem_euclid(d), d))
+                            .ok_or(CalcError::Overflow)?;
+                        stack.0.push(Rational64::from(n.div_euclid(d)));
+                        stack.0.push(r)
+                    }
+                    Operator::Round => {
+                        let a = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        stack.0.push(round(&a, calculator.settings.round_mode)?)
+                    }
+                    Operator::OnDenominator => {
+                        // The result is stored reduced, so 2/4 on quarters
+                        // shows as 1/2.
+                        let d = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let x = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let d = integer(&d)?;
+                        if d <= 0 {
+                            return Err(CalcError::MathError);
+                        }
+                        let d = Rational64::from(d);
+                        let steps = x.checked_mul(&d).ok_or(CalcError::Overflow)?;
+                        let steps = round(&steps, calculator.settings.round_mode)?;
+                        stack
+                            .0
+                            .push(steps.checked_div(&d).ok_or(CalcError::Overflow)?)
+                    }
+                    Operator::SigFig => {
+                        let n = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let x = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let n = integer(&n)?;
+                        if n <= 0 {
+                            return Err(CalcError::MathError);
+                        }
+                        stack
+                            .0
+                            .push(round_significant(&x, n, calculator.settings.round_mode)?)
+                    }
+                    Operator::ShowPercent => {
+                        let a = stack.last().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let percent = a
+                            .checked_mul(&Rational64::from(100))
+                            .ok_or(CalcError::Overflow)?;
+                        let text = if percent.is_integer() {
+                            percent.to_string()
+                        } else {
+                            format_decimal(&percent, 2, calculator.settings.round_mode)?
+                        };
+                        calculator.emitted.push(text + "%")
+                    }
+                    Operator::ShowBase => {
+                        // Only the base is consumed; the value stays put.
+                        let b = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let v = stack.last().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let b = u32::try_from(integer(&b)?)
+                            .ok()
+                            .filter(|b| (2..=36).contains(b))
+                            .ok_or(CalcError::MathError)?;
+                        calculator.emitted.push(format_base(integer(v)?, b))
                     }
                     Operator::Clear => stack = Stack(vec![]),
+                    Operator::IsSorted => {
+                        let sorted = stack.0.windows(2).all(|w| w[0] <= w[1]);
+                        stack.0.push(Rational64::from(i64::from(sorted)))
+                    }
+                    Operator::Count => {
+                        // The needle is the top value; only the values below
+                        // it are searched, and they are left in place.
+                        let x = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let count = stack.0.iter().filter(|y| **y == x).count();
+                        stack.0.push(Rational64::from(count as i64))
+                    }
+                    Operator::StackMax => {
+                        let max = stack
+                            .0
+                            .iter()
+                            .max()
+                            .ok_or(CalcError::NotEnoughItemsInStack)?;
+                        stack.0.push(*max)
+                    }
+                    Operator::StackMin => {
+                        let min = stack
+                            .0
+                            .iter()
+                            .min()
+                            .ok_or(CalcError::NotEnoughItemsInStack)?;
+                        stack.0.push(*min)
+                    }
+                    Operator::Sort => stack.0.sort(),
+                    Operator::SortDesc => stack.0.sort_by(|a, b| b.cmp(a)),
+                    Operator::Find => {
+                        // Searches down from the top, so the nearest copy
+                        // wins; -1 means there is none.
+                        let x = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let depth = stack.0.iter().rev().position(|y| *y == x);
+                        stack
+                            .0
+                            .push(Rational64::from(depth.map_or(-1, |d| d as i64)))
+                    }
+                    Operator::Dedup => stack.0.dedup(),
+                    Operator::Unique => {
+                        // Values keep the order in which they first appear
+                        // from the bottom; later copies are dropped.
+                        let mut seen = HashSet::new();
+                        stack.0.retain(|x| seen.insert(*x))
+                    }
+                    Operator::InsertAt => {
+                        // With `n` and `v` popped, `v` goes in at index
+                        // `len - n`, leaving exactly `n` values above it: 0
+                        // puts it back on top, `len` puts it at the bottom.
+                        let n = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let v = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let index = usize::try_from(integer(&n)?)
+                            .ok()
+                            .and_then(|n| stack.0.len().checked_sub(n))
+                            .ok_or(CalcError::MathError)?;
+                        stack.0.insert(index, v)
+                    }
+                    Operator::RemoveAt => {
+                        // The removed value is discarded, not pushed back:
+                        // 0 drops the top, like `p`.
+                        let n = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let index = usize::try_from(integer(&n)?)
+                            .ok()
+                            .and_then(|n| stack.0.len().checked_sub(n + 1))
+                            .ok_or(CalcError::MathError)?;
+                        stack.0.remove(index);
+                    }
+                    Operator::SwapAt => {
+                        // Both are depths counted once the indices have been
+                        // popped: 0 is the top, `len - 1` the bottom.
+                        let j = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let i = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let len = stack.0.len();
+                        let index = |depth: &Rational64| {
+                            usize::try_from(integer(depth)?)
+                                .ok()
+                                .filter(|depth| *depth < len)
+                                .map(|depth| len - 1 - depth)
+                                .ok_or(CalcError::MathError)
+                        };
+                        let (i, j) = (index(&i)?, index(&j)?);
+                        stack.0.swap(i, j)
+                    }
+                    Operator::RotateStack => {
+                        // Rotates the stack, read bottom to top, left by `n`
+                        // places, or right for negative `n`, wrapping `n`
+                        // around the length.
+                        let n = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let n = integer(&n)?;
+                        if !stack.0.is_empty() {
+                            let len = stack.0.len() as i64;
+                            stack.0.rotate_left(n.rem_euclid(len) as usize)
+                        }
+                    }
+                    Operator::Range => {
+                        let step = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let end = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let start = stack.0.pop().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let ascending = step.is_positive();
+                        if step == Rational64::from(0)
+                            || (start > end && ascending)
+                            || (start < end && !ascending)
+                        {
+                            return Err(CalcError::MathError);
+                        }
+                        let mut x = start;
+                        while (ascending && x <= end) || (!ascending && x >= end) {
+                            // Checked as we go so that a huge range fails
+                            // quickly instead of exhausting memory.
+                            if stack.0.len() >= calculator.settings.max_depth {
+                                return Err(CalcError::StackOverflow);
+                            }
+                            stack.0.push(x);
+                            match x.checked_add(&step) {
+                                Some(next) => x = next,
+                                None => break,
+                            }
+                        }
+                    }
+                    Operator::Fold(m) => {
+                        // Left to right: the first two values are combined,
+                        // then that result with the third, and so on.
+                        let mut values = std::mem::take(&mut stack.0).into_iter();
+                        let first = values.next().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        let folded = values.try_fold(first, |acc, x| {
+                            Ok(m.apply(vec![acc, x], 1, calculator)?[0])
+                        })?;
+                        stack.0.push(folded)
+                    }
+                    Operator::Map(m) => {
+                        // Each value is transformed on a stack of its own,
+                        // so the macro cannot see or disturb its neighbours.
+                        stack.0 = std::mem::take(&mut stack.0)
+                            .into_iter()
+                            .map(|x| Ok(m.apply(vec![x], 1, calculator)?[0]))
+                            .collect::<Result<_, CalcError>>()?
+                    }
+                    Operator::Dup => {
+                        let a = stack.last().ok_or(CalcError::NotEnoughItemsInStack)?;
+                        stack.0.push(*a)
+                    }
                     Operator::Pop => {
                         stack.0.pop();
                     }
                 },
             };
+            if stack.0.len() > calculator.settings.max_depth {
+                return Err(CalcError::StackOverflow);
+            }
+            let limit = calculator.settings.denominator_limit;
+            if matches!(item, Item::Operator(_)) && limit > 0 {
+                if let Some(top) = stack.last().filter(|top| *top.denom() > limit) {
+                    return Err(CalcError::DenominatorTooLarge(*top.denom()));
+                }
+            }
             Ok(stack)
-        });
+        })
+    }
+}
 
-        result
+/// Writes the line in canonical RPN form, items separated by single spaces,
+/// so infix input such as `3 + 6` shows as `3 6 +`.
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (n, item) in self.0.iter().enumerate() {
+            if n > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{item}")?;
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum CalcError {
-    NotEnoughItemsInStack,
-    MathError,
+/// A SplitMix64 pseudo-random generator. It is small, fast and reproducible
+/// from a seed, which is all `rand` needs; it is not suitable for anything
+/// security related.
+#[cfg(feature = "rand")]
+#[derive(Debug, Clone, PartialEq)]
+struct Rng(u64);
+
+#[cfg(feature = "rand")]
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A uniform integer in `[lo, hi]`, which must not be empty.
+    fn next_in_range(&mut self, lo: i64, hi: i64) -> i64 {
+        let span = (hi as i128 - lo as i128 + 1) as u128;
+        let offset = (self.next_u64() as u128 * span) >> 64;
+        (lo as i128 + offset as i128) as i64
+    }
+
+    /// A uniform value in `[0, 1)`, as a multiple of `1/2^32`.
+    fn next_rational(&mut self) -> Rational64 {
+        Rational64::new((self.next_u64() >> 32) as i64, 1 << 32)
+    }
 }
 
-#[derive(Debug, PartialEq, Clone)]
-struct Stack(Vec<Rational64>);
+/// Seeds from the OS-randomised hasher keys mixed with the current time.
+#[cfg(feature = "rand")]
+impl Default for Rng {
+    fn default() -> Self {
+        use std::hash::{BuildHasher, Hasher};
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        if let Ok(now) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            hasher.write_u128(now.as_nanos());
+        }
+        Rng(hasher.finish())
+    }
+}
 
-impl Stack {
-    fn new() -> Self {
-        Stack(Vec::new())
+/// `base` raised to the integer power `exp`, by repeated squaring. Every
+/// multiplication is checked, so an overflow is reported as soon as it
+/// happens instead of after building a huge intermediate.
+fn checked_pow(base: &Rational64, exp: i64) -> Result<Rational64, CalcError> {
+    let mut base = if exp < 0 {
+        if *base == Rational64::from(0) {
+            return Err(CalcError::DivideByZero);
+        }
+        // `recip` moves the sign to the numerator by negating, which
+        // `i64::MIN` can't survive.
+        if *base.numer() == i64::MIN {
+            return Err(CalcError::Overflow);
+        }
+        base.recip()
+    } else {
+        *base
+    };
+    let mut exp = exp.unsigned_abs();
+    let mut result = Rational64::from(1);
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = result.checked_mul(&base).ok_or(CalcError::Overflow)?;
+        }
+        exp >>= 1;
+        if exp > 0 {
+            base = base.checked_mul(&base).ok_or(CalcError::Overflow)?;
+        }
     }
-    fn last(&self) -> Option<&Rational64> {
-        self.0.last()
+    Ok(result)
+}
+
+/// The value as an `i64`, for operators that only make sense on integers.
+fn integer(x: &Rational64) -> Result<i64, CalcError> {
+    if x.is_integer() {
+        Ok(x.to_integer())
+    } else {
+        Err(CalcError::MathError)
     }
 }
 
-impl fmt::Display for Stack {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.0.iter().for_each(|i| {
-            let int = i.to_integer();
-            let frac = i % 1;
+/// The greatest common divisor of `a` and `b` by Euclid's algorithm, along
+/// with the number of divisions it took. Each step replaces `(a, b)` with
+/// `(b, a mod b)` until the remainder is zero, so `48 18` takes three steps
+/// (remainders 12, 6 and 0). Signs are ignored and `gcd(0, 0)` is 0 after no
+/// steps at all.
+fn euclid(a: i64, b: i64) -> Result<(i64, i64), CalcError> {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    let mut steps = 0;
+    while b != 0 {
+        (a, b) = (b, a % b);
+        steps += 1;
+    }
+    Ok((i64::try_from(a).map_err(|_| CalcError::Overflow)?, steps))
+}
 
-            write!(f, " {int}+{frac}").unwrap();
-        });
-        Ok(())
+/// `a * b / 2` for non-negative `a` and `b`, one of which is even, halving
+/// before multiplying so only a result that doesn't fit overflows.
+fn half_product(a: i64, b: i64) -> Option<i64> {
+    if a % 2 == 0 {
+        (a / 2).checked_mul(b)
+    } else {
+        a.checked_mul(b / 2)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// The decimal digits of `n`, least significant first and ignoring the sign.
+/// Zero has the single digit 0.
+fn decimal_digits(n: i64) -> Vec<u8> {
+    let mut rest = n.unsigned_abs();
+    let mut digits = vec![(rest % 10) as u8];
+    rest /= 10;
+    while rest != 0 {
+        digits.push((rest % 10) as u8);
+        rest /= 10;
+    }
+    digits
+}
+
+/// The prime factorization of `n`, as `(prime, power)` pairs in increasing
+/// order of prime. 1 (and 0) have no prime factors.
+///
+/// Composites are split with Pollard's rho rather than trial division, which
+/// would take seconds for a product of two large primes near `i64::MAX`.
+fn prime_factors(n: u64) -> Vec<(u64, u32)> {
+    fn split(n: u64, primes: &mut Vec<u64>) {
+        if n <= 1 {
+            return;
+        }
+        if is_prime(n) {
+            primes.push(n);
+            return;
+        }
+        let d = pollard_rho(n);
+        split(d, primes);
+        split(n / d, primes);
+    }
+    let mut primes = Vec::new();
+    split(n, &mut primes);
+    primes.sort();
+    primes
+        .chunk_by(|a, b| a == b)
+        .map(|run| (run[0], run.len() as u32))
+        .collect()
+}
+
+/// A factor of the composite `n` other than 1 and `n`, by Pollard's rho
+/// with Floyd's cycle detection, trying another polynomial whenever one
+/// only finds `n` itself.
+fn pollard_rho(n: u64) -> u64 {
+    if n.is_multiple_of(2) {
+        return 2;
+    }
+    for c in 1.. {
+        let f = |x: u64| ((u128::from(mul_mod(x, x, n)) + c) % u128::from(n)) as u64;
+        let (mut x, mut y, mut d) = (2, 2, 1);
+        while d == 1 {
+            x = f(x);
+            y = f(f(y));
+            d = num::integer::gcd(x.abs_diff(y), n);
+        }
+        if d != n {
+            return d;
+        }
+    }
+    unreachable!("some polynomial always splits a composite")
+}
+
+/// Whether `n` is prime, by Miller-Rabin. Using the first twelve primes as
+/// witnesses makes the test exact for every `u64`.
+fn is_prime(n: u64) -> bool {
+    const WITNESSES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+    if n < 2 {
+        return false;
+    }
+    if let Some(p) = WITNESSES.iter().find(|p| n.is_multiple_of(**p)) {
+        return n == *p;
+    }
+    // n - 1 = d * 2^s with d odd.
+    let s = (n - 1).trailing_zeros();
+    let d = (n - 1) >> s;
+    WITNESSES.iter().all(|&a| {
+        let mut x = pow_mod(a, d, n);
+        if x == 1 || x == n - 1 {
+            return true;
+        }
+        (1..s).any(|_| {
+            x = mul_mod(x, x, n);
+            x == n - 1
+        })
+    })
+}
+
+/// `a * b mod m`, widened so the product can't overflow.
+fn mul_mod(a: u64, b: u64, m: u64) -> u64 {
+    (u128::from(a) * u128::from(b) % u128::from(m)) as u64
+}
+
+/// `base^exp mod m`, by repeated squaring.
+fn pow_mod(mut base: u64, mut exp: u64, m: u64) -> u64 {
+    let mut result = 1 % m;
+    base %= m;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = mul_mod(result, base, m);
+        }
+        base = mul_mod(base, base, m);
+        exp >>= 1;
+    }
+    result
+}
+
+/// The sum of the decimal digits of `n`, ignoring the sign.
+fn digit_sum(n: i64) -> i64 {
+    decimal_digits(n).into_iter().map(i64::from).sum()
+}
+
+/// The inverse of `a` modulo `m`, in `[0, m)`, by the extended Euclidean
+/// algorithm. Only exists when `a` and `m` are coprime.
+fn mod_inverse(a: i64, m: i64) -> Result<i64, CalcError> {
+    // Invariant: r = s * a (mod m) for both rows. i128 keeps the
+    // coefficients from overflowing on their way through.
+    let (mut r0, mut r1) = (m as i128, (a % m) as i128);
+    let (mut s0, mut s1) = (0i128, 1i128);
+    while r1 != 0 {
+        let q = r0 / r1;
+        (r0, r1) = (r1, r0 - q * r1);
+        (s0, s1) = (s1, s0 - q * s1);
+    }
+    if r0 != 1 {
+        return Err(CalcError::MathError);
+    }
+    Ok(s0.rem_euclid(m as i128) as i64)
+}
+
+/// The closest fraction to `x` whose denominator is at most `max_denom`,
+/// found by walking the continued fraction expansion of `x` and then picking
+/// between the last convergent and the best semiconvergent that fits.
+fn limit_denominator(x: &Rational64, max_denom: i64) -> Result<Rational64, CalcError> {
+    if *x.denom() <= max_denom {
+        return Ok(*x);
+    }
+    let (max_denom, exact) = (
+        max_denom as i128,
+        Ratio::<i128>::new(*x.numer() as i128, *x.denom() as i128),
+    );
+    let (mut p0, mut q0, mut p1, mut q1) = (0i128, 1i128, 1i128, 0i128);
+    let (mut n, mut d) = (*exact.numer(), *exact.denom());
+    loop {
+        let a = n.div_euclid(d);
+        let q2 = q0 + a * q1;
+        if q2 > max_denom {
+            break;
+        }
+        (p0, q0, p1, q1) = (p1, q1, p0 + a * p1, q2);
+        (n, d) = (d, n - a * d);
+    }
+    let k = (max_denom - q0) / q1;
+    let semiconvergent = Ratio::new(p0 + k * p1, q0 + k * q1);
+    let convergent = Ratio::new(p1, q1);
+    let best = if (semiconvergent - exact).abs() < (convergent - exact).abs() {
+        semiconvergent
+    } else {
+        convergent
+    };
+    Ok(Rational64::new(
+        (*best.numer())
+            .try_into()
+            .map_err(|_| CalcError::Overflow)?,
+        (*best.denom())
+            .try_into()
+            .map_err(|_| CalcError::Overflow)?,
+    ))
+}
+
+/// The convergents of the continued fraction expansion of `x`, from its
+/// floor to `x` itself. A rational's expansion is finite, so e.g. `355/113`
+/// gives `3`, `22/7` and `355/113`, and an integer only itself.
+fn convergents(x: &Rational64) -> Result<Vec<Rational64>, CalcError> {
+    let (mut p0, mut q0, mut p1, mut q1) = (0i128, 1i128, 1i128, 0i128);
+    let (mut n, mut d) = (*x.numer() as i128, *x.denom() as i128);
+    let mut convergents = Vec::new();
+    while d != 0 {
+        let a = n.div_euclid(d);
+        (p0, q0, p1, q1) = (p1, q1, p0 + a * p1, q0 + a * q1);
+        (n, d) = (d, n - a * d);
+        convergents.push(Rational64::new(
+            p1.try_into().map_err(|_| CalcError::Overflow)?,
+            q1.try_into().map_err(|_| CalcError::Overflow)?,
+        ));
+    }
+    Ok(convergents)
+}
+
+/// Rounds `x` to `figures` significant figures according to `mode`, e.g.
+/// 12345 to 3 is 12300 and 1/3 to 2 is 33/100.
+fn round_significant(
+    x: &Rational64,
+    figures: i64,
+    mode: RoundMode,
+) -> Result<Rational64, CalcError> {
+    if *x == Rational64::from(0) {
+        return Ok(*x);
+    }
+    // The power of ten of the leading digit: 10^e <= |x| < 10^(e+1). A
+    // power too large to represent is certainly above |x|. Negative values
+    // are compared as they are, since `i64::MIN` has no absolute value.
+    let ten = Rational64::from(10);
+    let within = |p: &Rational64| if x.is_negative() { -p >= *x } else { p <= x };
+    let mut e = 0i64;
+    while checked_pow(&ten, e + 1).is_ok_and(|p| within(&p)) {
+        e += 1;
+    }
+    while !within(&checked_pow(&ten, e)?) {
+        e -= 1;
+    }
+    let scale = checked_pow(&ten, figures.saturating_sub(1).saturating_sub(e))?;
+    let scaled = x.checked_mul(&scale).ok_or(CalcError::Overflow)?;
+    round(&scaled, mode)?
+        .checked_div(&scale)
+        .ok_or(CalcError::Overflow)
+}
+
+/// Rounds `x` to an integer according to `mode`.
+///
+/// Works from the floor and the remainder of the numerator, as `Ratio`'s own
+/// `round`, `floor` and `ceil` overflow for values near `i64::MAX`.
+fn round(x: &Rational64, mode: RoundMode) -> Result<Rational64, CalcError> {
+    let (n, d) = (*x.numer(), *x.denom());
+    // The denominator is always positive, so neither of these can overflow.
+    let (floor, rem) = (n.div_euclid(d), n.rem_euclid(d));
+    // How the fractional part `rem / d` compares with a half, without
+    // doubling `rem`.
+    let half = rem.cmp(&(d - rem));
+    let up = match mode {
+        _ if rem == 0 => false,
+        RoundMode::HalfUp => half == Ordering::Greater || (half == Ordering::Equal && n > 0),
+        RoundMode::HalfEven => {
+            half == Ordering::Greater || (half == Ordering::Equal && floor % 2 != 0)
+        }
+        RoundMode::TowardZero => n < 0,
+        RoundMode::AwayFromZero => n > 0,
+    };
+    let rounded = if up {
+        floor.checked_add(1).ok_or(CalcError::Overflow)?
+    } else {
+        floor
+    };
+    Ok(Rational64::from(rounded))
+}
+
+/// Writes `n` in the given base, from 2 to 36, using lowercase letters for
+/// digits past 9 and a leading `-` for negatives, e.g. 255 in base 16 is `ff`.
+fn format_base(n: i64, base: u32) -> String {
+    let mut rest = n.unsigned_abs();
+    let mut digits = Vec::new();
+    loop {
+        digits.push(char::from_digit((rest % base as u64) as u32, base).unwrap());
+        rest /= base as u64;
+        if rest == 0 {
+            break;
+        }
+    }
+    if n < 0 {
+        digits.push('-');
+    }
+    digits.iter().rev().collect()
+}
+
+/// Writes `x` as a decimal with exactly `places` digits after the point,
+/// rounding the last digit according to `mode`, e.g. `1/3` to 2 places is
+/// `0.33` when rounding half up.
+fn format_decimal(x: &Rational64, places: u32, mode: RoundMode) -> Result<String, CalcError> {
+    let scale = 10i64.checked_pow(places).ok_or(CalcError::Overflow)?;
+    let scaled = round(
+        &x.checked_mul(&Rational64::from(scale))
+            .ok_or(CalcError::Overflow)?,
+        mode,
+    )?
+    .to_integer();
+    let sign = if scaled < 0 { "-" } else { "" };
+    let (int, frac) = (
+        scaled.unsigned_abs() / scale as u64,
+        scaled.unsigned_abs() % scale as u64,
+    );
+    if places == 0 {
+        return Ok(format!("{sign}{int}"));
+    }
+    Ok(format!(
+        "{sign}{int}.{frac:0width$}",
+        width = places as usize
+    ))
+}
+
+thread_local! {
+    /// Set while `recover` runs, so the panic hook stays quiet about panics
+    /// that are about to be reported as errors anyway.
+    static RECOVERING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f`, turning a panic into `CalcError::Internal` rather than letting
+/// it unwind further. The default panic message and backtrace are skipped,
+/// as the error already carries the message.
+fn recover<T>(f: impl FnOnce() -> Result<T, CalcError>) -> Result<T, CalcError> {
+    static QUIET_HOOK: Once = Once::new();
+    QUIET_HOOK.call_once(|| {
+        let default = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !RECOVERING.with(Cell::get) {
+                default(info)
+            }
+        }));
+    });
+    let was_recovering = RECOVERING.replace(true);
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    RECOVERING.set(was_recovering);
+    result.map_err(|payload| CalcError::Internal(panic_message(payload.as_ref())))?
+}
+
+/// The message a panic was raised with, if it had one.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Converts a value to the nearest `f64`, for operations that have no exact
+/// rational answer.
+fn to_float(x: &Rational64) -> f64 {
+    x.to_f64().unwrap_or(f64::NAN)
+}
+
+/// Approximates a float result as a rational, unless exact mode forbids it.
+/// The rounding mode plays no part: the float is already rounded, and the
+/// closest fraction to it has no ties to break.
+fn approximate(x: f64, settings: &Settings) -> Result<Rational64, CalcError> {
+    if settings.exact {
+        return Err(CalcError::InexactResult);
+    }
+    from_float(x)
+}
+
+/// The square root of `x`: exact when possible, otherwise approximated.
+fn sqrt(x: &Rational64, settings: &Settings) -> Result<Rational64, CalcError> {
+    if *x < Rational64::from(0) {
+        return Err(CalcError::MathError);
+    }
+    match exact_sqrt(x) {
+        Some(root) => Ok(root),
+        None => approximate(to_float(x).sqrt(), settings),
+    }
+}
+
+/// The real `n`th root of `x`: exact when possible, otherwise approximated.
+/// Negative values only have one for odd `n`.
+fn nth_root(x: &Rational64, n: u32, settings: &Settings) -> Result<Rational64, CalcError> {
+    if x.is_negative() {
+        if n.is_multiple_of(2) {
+            return Err(CalcError::MathError);
+        }
+        let magnitude = Rational64::from(0)
+            .checked_sub(x)
+            .ok_or(CalcError::Overflow)?;
+        return Ok(-nth_root(&magnitude, n, settings)?);
+    }
+    let numer = x.numer().nth_root(n);
+    let denom = x.denom().nth_root(n);
+    let is_exact =
+        numer.checked_pow(n) == Some(*x.numer()) && denom.checked_pow(n) == Some(*x.denom());
+    if is_exact {
+        return Ok(Rational64::new(numer, denom));
+    }
+    approximate(to_float(x).powf(1.0 / f64::from(n)), settings)
+}
+
+/// Adds up the values, failing rather than panicking on overflow.
+fn checked_sum<'a>(
+    values: impl IntoIterator<Item = &'a Rational64>,
+) -> Result<Rational64, CalcError> {
+    values
+        .into_iter()
+        .try_fold(Rational64::from(0), |acc, x| acc.checked_add(x))
+        .ok_or(CalcError::Overflow)
+}
+
+/// The population variance of the values, i.e. the mean squared distance
+/// from their mean (dividing by `n`, not `n - 1`).
+fn variance(values: &[Rational64]) -> Result<Rational64, CalcError> {
+    if values.is_empty() {
+        return Err(CalcError::NotEnoughItemsInStack);
+    }
+    let n = Rational64::from(values.len() as i64);
+    let mean = checked_sum(values)?
+        .checked_div(&n)
+        .ok_or(CalcError::Overflow)?;
+    let squares = values
+        .iter()
+        .map(|x| {
+            x.checked_sub(&mean)
+                .and_then(|d| d.checked_mul(&d))
+                .ok_or(CalcError::Overflow)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    checked_sum(&squares)?
+        .checked_div(&n)
+        .ok_or(CalcError::Overflow)
+}
+
+/// The middle value once sorted, or the exact mean of the two middle values
+/// when there is an even number of them.
+fn median(values: &[Rational64]) -> Result<Rational64, CalcError> {
+    if values.is_empty() {
+        return Err(CalcError::NotEnoughItemsInStack);
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort();
+    let mid = sorted.len() / 2;
+    if sorted.len() % 2 == 1 {
+        return Ok(sorted[mid]);
+    }
+    // Two large values can overflow their sum but not the half-way step
+    // between them, and vice versa for two far apart.
+    let (a, b, two) = (sorted[mid - 1], sorted[mid], Rational64::from(2));
+    a.checked_add(&b)
+        .and_then(|sum| sum.checked_div(&two))
+        .or_else(|| b.checked_sub(&a)?.checked_div(&two)?.checked_add(&a))
+        .ok_or(CalcError::Overflow)
+}
+
+/// The most frequent values in ascending order: just one unless several are
+/// tied.
+fn modes(values: &[Rational64]) -> Result<Vec<Rational64>, CalcError> {
+    let mut counts = HashMap::new();
+    for x in values {
+        *counts.entry(*x).or_insert(0usize) += 1;
+    }
+    let most = counts
+        .values()
+        .copied()
+        .max()
+        .ok_or(CalcError::NotEnoughItemsInStack)?;
+    let mut modes: Vec<_> = counts
+        .into_iter()
+        .filter(|(_, count)| *count == most)
+        .map(|(x, _)| x)
+        .collect();
+    modes.sort();
+    Ok(modes)
+}
+
+/// The dot product of the two halves of the values, pairing element `i` of
+/// the bottom half with element `i` of the top half, so `1 2 3 4` is
+/// `1*3 + 2*4`. An odd number of values has no halves to pair.
+fn dot(values: &[Rational64]) -> Result<Rational64, CalcError> {
+    if !values.len().is_multiple_of(2) {
+        return Err(CalcError::MathError);
+    }
+    let (bottom, top) = values.split_at(values.len() / 2);
+    let products = bottom
+        .iter()
+        .zip(top)
+        .map(|(a, b)| a.checked_mul(b).ok_or(CalcError::Overflow))
+        .collect::<Result<Vec<_>, _>>()?;
+    checked_sum(&products)
+}
+
+/// The sum of each value times its weight, with the values given as
+/// interleaved pairs from the bottom up: `v1 w1 v2 w2 ...`, so `2 3 4 5` is
+/// `2*3 + 4*5`. An odd number of values leaves a value without a weight.
+fn weighted_sum(values: &[Rational64]) -> Result<Rational64, CalcError> {
+    if !values.len().is_multiple_of(2) {
+        return Err(CalcError::MathError);
+    }
+    let products = values
+        .chunks(2)
+        .map(|pair| pair[0].checked_mul(&pair[1]).ok_or(CalcError::Overflow))
+        .collect::<Result<Vec<_>, _>>()?;
+    checked_sum(&products)
+}
+
+/// Evaluates the polynomial with the given coefficients at `x` using
+/// Horner's method. Coefficients run from the highest degree down to the
+/// constant term, so `[1, -3, 2]` is `x^2 - 3x + 2`; none at all is the zero
+/// polynomial.
+fn poly_val(coefficients: &[Rational64], x: &Rational64) -> Result<Rational64, CalcError> {
+    coefficients
+        .iter()
+        .try_fold(Rational64::from(0), |acc, c| {
+            acc.checked_mul(x).and_then(|acc| acc.checked_add(c))
+        })
+        .ok_or(CalcError::Overflow)
+}
+
+/// The sum of the first `n` terms of the geometric series starting at `a`
+/// with ratio `r`, by the closed form `a (1 - r^n) / (1 - r)`, or `a n` when
+/// `r` is 1.
+fn geometric_sum(a: &Rational64, r: &Rational64, n: i64) -> Result<Rational64, CalcError> {
+    if n < 0 {
+        return Err(CalcError::MathError);
+    }
+    let one = Rational64::from(1);
+    if *r == one {
+        return a
+            .checked_mul(&Rational64::from(n))
+            .ok_or(CalcError::Overflow);
+    }
+    let numer = one
+        .checked_sub(&checked_pow(r, n)?)
+        .ok_or(CalcError::Overflow)?;
+    a.checked_mul(&numer)
+        .and_then(|x| x.checked_div(&one.checked_sub(r)?))
+        .ok_or(CalcError::Overflow)
+}
+
+/// The coefficients of the derivative of the polynomial, in the same
+/// highest-degree-first order as [`poly_val`]. The derivative of a constant
+/// (or of the empty, zero polynomial) is `[0]` rather than no coefficients,
+/// so the stack is never left empty.
+fn poly_deriv(coefficients: &[Rational64]) -> Result<Vec<Rational64>, CalcError> {
+    let Some(degree) = coefficients.len().checked_sub(1).filter(|&d| d > 0) else {
+        return Ok(vec![Rational64::from(0)]);
+    };
+    coefficients[..degree]
+        .iter()
+        .zip((1..=degree as i64).rev())
+        .map(|(c, power)| {
+            c.checked_mul(&Rational64::from(power))
+                .ok_or(CalcError::Overflow)
+        })
+        .collect()
+}
+
+/// The reciprocal of the sum of reciprocals, as for resistors in parallel:
+/// `2 2` gives 1. Zero values, or reciprocals that cancel out, have no
+/// parallel sum.
+fn parallel_sum(values: &[Rational64]) -> Result<Rational64, CalcError> {
+    if values.is_empty() {
+        return Err(CalcError::NotEnoughItemsInStack);
+    }
+    let reciprocals = values
+        .iter()
+        .map(reciprocal)
+        .collect::<Result<Vec<_>, _>>()?;
+    reciprocal(&checked_sum(&reciprocals)?)
+}
+
+/// `1/x`, failing rather than negating an `i64::MIN` numerator, which
+/// `recip` and even `checked_div` do unchecked.
+fn reciprocal(x: &Rational64) -> Result<Rational64, CalcError> {
+    match *x.numer() {
+        0 => Err(CalcError::DivideByZero),
+        i64::MIN => Err(CalcError::Overflow),
+        n => Ok(Rational64::new(*x.denom() * n.signum(), n.abs())),
+    }
+}
+
+/// The harmonic mean of the values, `n / sum(1/x)`.
+fn harmonic_mean(values: &[Rational64]) -> Result<Rational64, CalcError> {
+    if values.is_empty() {
+        return Err(CalcError::NotEnoughItemsInStack);
+    }
+    let reciprocals = values
+        .iter()
+        .map(reciprocal)
+        .collect::<Result<Vec<_>, _>>()?;
+    let total = checked_sum(&reciprocals)?;
+    if total == Rational64::from(0) {
+        return Err(CalcError::DivideByZero);
+    }
+    Rational64::from(values.len() as i64)
+        .checked_div(&total)
+        .ok_or(CalcError::Overflow)
+}
+
+/// The geometric mean of non-negative values, the `n`th root of their
+/// product, which is exact when the root is, like `root`. A product too large
+/// to hold is worked out in floats as the mean of the logarithms instead.
+fn geometric_mean(values: &[Rational64], settings: &Settings) -> Result<Rational64, CalcError> {
+    if values.is_empty() {
+        return Err(CalcError::NotEnoughItemsInStack);
+    }
+    if values.iter().any(|x| *x < Rational64::from(0)) {
+        return Err(CalcError::MathError);
+    }
+    if values.iter().any(|x| *x == Rational64::from(0)) {
+        return Ok(Rational64::from(0));
+    }
+    let product = values
+        .iter()
+        .try_fold(Rational64::from(1), |acc, x| acc.checked_mul(x));
+    if let (Some(product), Ok(n)) = (product, u32::try_from(values.len())) {
+        return nth_root(&product, n, settings);
+    }
+    let mean_ln = values.iter().map(|x| to_float(x).ln()).sum::<f64>() / values.len() as f64;
+    approximate(mean_ln.exp(), settings)
+}
+
+/// The square root of a non-negative value, if both its numerator and
+/// denominator are perfect squares.
+fn exact_sqrt(x: &Rational64) -> Option<Rational64> {
+    let numer = x.numer().sqrt();
+    let denom = x.denom().sqrt();
+    (numer * numer == *x.numer() && denom * denom == *x.denom())
+        .then(|| Rational64::new(numer, denom))
+}
+
+/// The exact value of `x` as a `Rational64`, if its denominator (always a
+/// power of two) and numerator fit. `0.1` is really
+/// `3602879701896397/36028797018963968`.
+fn exact_float(x: f64) -> Option<Rational64> {
+    if !x.is_finite() {
+        return None;
+    }
+    // Doubling is exact, so this just moves the binary point.
+    let (mut numer, mut exp) = (x, 0);
+    while numer.fract() != 0.0 && exp < 62 {
+        numer *= 2.0;
+        exp += 1;
+    }
+    (numer.fract() == 0.0 && numer.abs() < i64::MAX as f64)
+        .then(|| Rational64::new(numer as i64, 1 << exp))
+}
+
+/// Brings a float result back onto the stack as the closest `Rational64`,
+/// failing if it is infinite, NaN or too large to represent.
+fn from_float(x: f64) -> Result<Rational64, CalcError> {
+    if !x.is_finite() {
+        return Err(CalcError::MathError);
+    }
+    Rational64::approximate_float(x).ok_or(CalcError::MathError)
+}
+
+fn infix_sum(i: &str) -> IResult<&str, Vec<Item>> {
+    infix_binary(i, infix_product, |i| {
+        alt((
+            value(Operator::Add, tag("+")),
+            value(Operator::Subtract, tag("-")),
+        ))(i)
+    })
+}
+
+fn infix_product(i: &str) -> IResult<&str, Vec<Item>> {
+    infix_binary(i, infix_unary, |i| {
+        alt((
+            value(Operator::Multiply, tag("*")),
+            value(Operator::Divide, tag("/")),
+        ))(i)
+    })
+}
+
+fn infix_power(i: &str) -> IResult<&str, Vec<Item>> {
+    let (i, mut base) = infix_atom(i)?;
+    match preceded(
+        delimited(cc::multispace0, tag("^"), cc::multispace0),
+        infix_unary,
+    )(i)
+    {
+        Ok((i, mut exponent)) => {
+            base.append(&mut exponent);
+            base.push(Item::Operator(Operator::Power));
+            Ok((i, base))
+        }
+        Err(nom::Err::Error(_)) => Ok((i, base)),
+        Err(e) => Err(e),
+    }
+}
+
+/// A power with any number of signs in front. Signs apply after `^`, so
+/// `-2^2` is `-(2^2)`, but a negative number on its own is kept as a single
+/// literal.
+fn infix_unary(i: &str) -> IResult<&str, Vec<Item>> {
+    let power = pair(cc::multispace0, tag("^"));
+    alt((
+        map(terminated(cc::i64, not(power)), |n| {
+            vec![Item::Num(Rational64::from(n))]
+        }),
+        infix_power,
+        map(
+            preceded(pair(tag("-"), cc::multispace0), infix_unary),
+            |mut items| {
+                items.push(Item::Num(Rational64::from(-1)));
+                items.push(Item::Operator(Operator::Multiply));
+                items
+            },
+        ),
+        preceded(pair(tag("+"), cc::multispace0), infix_unary),
+    ))(i)
+}
+
+/// An unsigned number or a parenthesised expression.
+fn infix_atom(i: &str) -> IResult<&str, Vec<Item>> {
+    alt((
+        map_opt(cc::digit1, |n: &str| {
+            Some(vec![Item::Num(Rational64::from(n.parse::<i64>().ok()?))])
+        }),
+        delimited(
+            pair(tag("("), cc::multispace0),
+            infix_sum,
+            pair(cc::multispace0, tag(")")),
+        ),
+    ))(i)
+}
+
+/// Parses a left associative chain of `operand (operator operand)*`,
+/// emitting the operands and operators in postfix order.
+fn infix_binary<'a>(
+    i: &'a str,
+    operand: fn(&'a str) -> IResult<&'a str, Vec<Item>>,
+    operator: fn(&'a str) -> IResult<&'a str, Operator>,
+) -> IResult<&'a str, Vec<Item>> {
+    let (i, first) = operand(i)?;
+    fold_many0(
+        pair(
+            delimited(cc::multispace0, operator, cc::multispace0),
+            operand,
+        ),
+        move || first.clone(),
+        |mut items, (op, mut rhs)| {
+            items.append(&mut rhs);
+            items.push(Item::Operator(op));
+            items
+        },
+    )(i)
+}
+
+#[derive(Debug, PartialEq)]
+enum CalcError {
+    NotEnoughItemsInStack,
+    MathError,
+    /// The answer has no exact rational form and exact mode is on.
+    InexactResult,
+    UnknownWord(String),
+    /// An intermediate value no longer fits in a `Rational64`.
+    Overflow,
+    /// The stack would grow beyond the configured maximum depth.
+    StackOverflow,
+    /// A macro applied by `fold` or `map` left the wrong number
+    /// of values; holds the macro's name.
+    MacroArity(String),
+    /// A result's denominator is above the configured limit; holds the
+    /// offending denominator.
+    DenominatorTooLarge(i64),
+    DivideByZero,
+    /// The input is longer than the configured maximum; holds the maximum.
+    InputTooLong(usize),
+    /// The input could not be parsed; holds the text where parsing stopped.
+    ParseError(String),
+    /// Evaluation panicked, which is a bug; holds the panic message.
+    Internal(String),
+}
+
+impl CalcError {
+    /// A short identifier for the kind of error that, unlike the message,
+    /// is kept stable for tools reading the output.
+    fn code(&self) -> &'static str {
+        match self {
+            CalcError::NotEnoughItemsInStack => "E_STACK",
+            CalcError::MathError => "E_MATH",
+            CalcError::InexactResult => "E_INEXACT",
+            CalcError::UnknownWord(_) => "E_WORD",
+            CalcError::Overflow => "E_OVERFLOW",
+            CalcError::StackOverflow => "E_DEPTH",
+            CalcError::MacroArity(_) => "E_ARITY",
+            CalcError::DenominatorTooLarge(_) => "E_DENOM",
+            CalcError::DivideByZero => "E_DIV0",
+            CalcError::InputTooLong(_) => "E_LENGTH",
+            CalcError::ParseError(_) => "E_PARSE",
+            CalcError::Internal(_) => "E_INTERNAL",
+        }
+    }
+
+    /// The error as shown to the user, e.g.
+    /// `error[E_DIV0]: Division by zero!`.
+    fn report(&self) -> String {
+        format!("error[{}]: {self}", self.code())
+    }
+}
+
+impl fmt::Display for CalcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalcError::NotEnoughItemsInStack => write!(f, "Not enough items in stack!"),
+            CalcError::MathError => write!(f, "Math Error!"),
+            CalcError::InexactResult => write!(f, "Result is not exact!"),
+            CalcError::UnknownWord(name) => write!(f, "Unknown word {name}!"),
+            CalcError::Overflow => write!(f, "Overflow!"),
+            CalcError::StackOverflow => write!(f, "Stack is too deep!"),
+            CalcError::MacroArity(name) => {
+                write!(f, "Macro {name} left the wrong number of values!")
+            }
+            CalcError::DenominatorTooLarge(denom) => {
+                write!(f, "Denominator {denom} is too large!")
+            }
+            CalcError::DivideByZero => write!(f, "Division by zero!"),
+            CalcError::InputTooLong(max) => write!(f, "Input is longer than {max} bytes!"),
+            CalcError::ParseError(rest) if rest.is_empty() => write!(f, "Parsing Error!"),
+            CalcError::ParseError(rest) => write!(f, "Parsing Error at `{rest}`!"),
+            CalcError::Internal(message) => write!(f, "Internal error: {message}!"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct Stack(Vec<Rational64>);
+
+impl Default for Stack {
+    fn default() -> Self {
+        Stack::new()
+    }
+}
+
+impl Stack {
+    fn new() -> Self {
+        Stack(Vec::new())
+    }
+    fn last(&self) -> Option<&Rational64> {
+        self.0.last()
+    }
+}
+
+impl From<Vec<Rational64>> for Stack {
+    fn from(values: Vec<Rational64>) -> Self {
+        Stack(values)
+    }
+}
+
+impl From<Vec<i64>> for Stack {
+    fn from(values: Vec<i64>) -> Self {
+        Stack(values.into_iter().map(Rational64::from_integer).collect())
+    }
+}
+
+impl From<Stack> for Vec<Rational64> {
+    fn from(stack: Stack) -> Self {
+        stack.0
+    }
+}
+
+impl fmt::Display for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.display(Order::BottomFirst).fmt(f)
+    }
+}
+
+impl Stack {
+    /// Lists every element on its own line, prefixed by its depth. Index 0 is
+    /// the top of the stack, i.e. the value the next operator will consume.
+    fn dump(&self) -> String {
+        self.0
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(n, i)| format!("{n}: {}\n", MixedNumber(i)))
+            .collect()
+    }
+
+    /// Formats the stack in the given order without touching the stack itself.
+    fn display(&self, order: Order) -> StackDisplay<'_> {
+        StackDisplay {
+            stack: self,
+            order,
+            group: None,
+        }
+    }
+}
+
+/// Which end of the stack is printed first.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+enum Order {
+    #[default]
+    BottomFirst,
+    TopFirst,
+}
+
+struct StackDisplay<'a> {
+    stack: &'a Stack,
+    order: Order,
+    /// Separator for grouping the digits of whole numbers, if any.
+    group: Option<char>,
+}
+
+impl StackDisplay<'_> {
+    fn grouped(self, group: Option<char>) -> Self {
+        StackDisplay { group, ..self }
+    }
+}
+
+impl fmt::Display for StackDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let items: Box<dyn Iterator<Item = &Rational64>> = match self.order {
+            Order::BottomFirst => Box::new(self.stack.0.iter()),
+            Order::TopFirst => Box::new(self.stack.0.iter().rev()),
+        };
+        for (n, i) in items.enumerate() {
+            if n > 0 {
+                write!(f, " ")?;
+            }
+            match self.group {
+                Some(sep) if i.is_integer() => write!(f, "{}", group_digits(i.to_integer(), sep))?,
+                _ => write!(f, "{}", MixedNumber(i))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats a result, grouping the digits of a whole number with `group` if
+/// set. Fractions are always shown as they are.
+fn format_value(x: &Rational64, group: Option<char>) -> String {
+    match group {
+        Some(sep) if x.is_integer() => group_digits(x.to_integer(), sep),
+        _ => x.to_string(),
+    }
+}
+
+/// Writes `n` with `sep` between each group of three digits, e.g.
+/// `1,234,567`.
+fn group_digits(n: i64, sep: char) -> String {
+    let digits = n.unsigned_abs().to_string();
+    let mut grouped = String::new();
+    if n < 0 {
+        grouped.push('-');
+    }
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(sep);
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+/// Formats a value as a whole number, a proper fraction, or a whole part and
+/// a fraction joined with `+`, e.g. `3`, `1/2` and `1+1/2`.
+struct MixedNumber<'a>(&'a Rational64);
+
+impl fmt::Display for MixedNumber<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let int = self.0.to_integer();
+        let frac = self.0.fract();
+
+        if frac == Rational64::from_integer(0) {
+            write!(f, "{int}")
+        } else if int == 0 {
+            write!(f, "{frac}")
+        } else {
+            write!(f, "{int}+{frac}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_number_parsing() {
+        assert_eq!(
+            Item::parse("32"),
+            Ok(("", Item::Num(Rational64::from_integer(32))))
+        );
+        assert_eq!(
+            Item::parse("-73"),
+            Ok(("", Item::Num(Rational64::from_integer(-73))))
+        );
+    }
+    #[test]
+    fn test_operator_parsing() {
+        assert_eq!(Item::parse("+"), Ok(("", Item::Operator(Operator::Add))));
+        assert_eq!(Item::parse("/"), Ok(("", Item::Operator(Operator::Divide))));
+        assert_eq!(
+            Item::parse("-"),
+            Ok(("", Item::Operator(Operator::Subtract)))
+        );
+        assert_eq!(
+            Item::parse("*"),
+            Ok(("", Item::Operator(Operator::Multiply)))
+        );
+    }
+    #[test]
+    fn test_single_item_parsing() {
+        assert_eq!(
+            Line::parse("3"),
+            Ok(("", Line(vec![Item::Num(Rational64::from_integer(3))])))
+        );
+        assert_eq!(
+            Line::parse("+"),
+            Ok(("", Line(vec![Item::Operator(Operator::Add)])))
+        );
+        assert_eq!(
+            Line::parse("-"),
+            Ok(("", Line(vec![Item::Operator(Operator::Subtract)])))
+        );
+        assert_eq!(
+            Line::parse("*"),
+            Ok(("", Line(vec![Item::Operator(Operator::Multiply)])))
+        );
+        assert_eq!(
+            Line::parse("/"),
+            Ok(("", Line(vec![Item::Operator(Operator::Divide)])))
+        );
+        assert_eq!(
+            Line::parse("S"),
+            Ok(("", Line(vec![Item::Operator(Operator::Sum)])))
+        );
+        assert_eq!(
+            Line::parse("-"),
+            Ok(("", Line(vec![Item::Operator(Operator::Subtract)])))
+        );
+        assert_eq!(
+            Line::parse("^"),
+            Ok(("", Line(vec![Item::Operator(Operator::Power)])))
+        );
+    }
+    #[test]
+    fn test_multiple_item_parsing() {
+        assert_eq!(
+            Line::parse("3 6 +"),
+            Ok((
+                "",
+                Line(vec![
+                    Item::Num(Rational64::from_integer(3)),
+                    Item::Num(Rational64::from_integer(6)),
+                    Item::Operator(Operator::Add)
+                ])
+            ))
+        );
+        assert_eq!(
+            Line::parse("47 9 + 15 *"),
+            Ok((
+                "",
+                Line(vec![
+                    Item::Num(Rational64::from_integer(47)),
+                    Item::Num(Rational64::from_integer(9)),
+                    Item::Operator(Operator::Add),
+                    Item::Num(Rational64::from_integer(15)),
+                    Item::Operator(Operator::Multiply)
+                ])
+            ))
+        );
+    }
+    #[test]
+    fn test_multiple_item_parsing_with_weird_spaces() {
+        assert_eq!(
+            Line::parse("3 6-2**"),
+            Ok((
+                "",
+                Line(vec![
+                    Item::Num(Rational64::from_integer(3)),
+                    Item::Num(Rational64::from_integer(6)),
+                    Item::Num(Rational64::from_integer(-2)),
+                    Item::Operator(Operator::Multiply),
+                    Item::Operator(Operator::Multiply)
+                ])
+            ))
+        );
+        assert_eq!(
+            Line::parse(" 3 6*2* "),
+            Ok((
+                "",
+                Line(vec![
+                    Item::Num(Rational64::from_integer(3)),
+                    Item::Num(Rational64::from_integer(6)),
+                    Item::Operator(Operator::Multiply),
+                    Item::Num(Rational64::from_integer(2)),
+                    Item::Operator(Operator::Multiply)
+                ])
+            ))
+        );
+        assert_eq!(
+            Line::parse(" 3 6+2** "),
+            Ok((
+                "",
+                Line(vec![
+                    Item::Num(Rational64::from_integer(3)),
+                    Item::Num(Rational64::from_integer(6)),
+                    Item::Num(Rational64::from_integer(2)),
+                    Item::Operator(Operator::Multiply),
+                    Item::Operator(Operator::Multiply)
+                ])
+            ))
+        );
+        assert_eq!(
+            Line::parse("3 6 2 S"),
+            Ok((
+                "",
+                Line(vec![
+                    Item::Num(Rational64::from_integer(3)),
+                    Item::Num(Rational64::from_integer(6)),
+                    Item::Num(Rational64::from_integer(2)),
+                    Item::Operator(Operator::Sum),
+                ])
+            ))
+        );
+        assert_eq!(
+            Line::parse("3 2 ^"),
+            Ok((
+                "",
+                Line(vec![
+                    Item::Num(Rational64::from_integer(3)),
+                    Item::Num(Rational64::from_integer(2)),
+                    Item::Operator(Operator::Power),
+                ])
+            ))
+        );
+    }
+    #[test]
+    fn test_calculating_simple_integers() {
+        assert_eq!(
+            Line::parse("3 6 +").unwrap().1.calc(Stack::new()).unwrap(),
+            Stack(vec![Rational64::from_integer(9)])
+        );
+        assert_eq!(
+            Line::parse("3 6 *").unwrap().1.calc(Stack::new()).unwrap(),
+            Stack(vec![Rational64::from_integer(18)])
+        );
+        assert_eq!(
+            Line::parse("3 6 + 2 *")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::from_integer(18)])
+        );
+        assert_eq!(
+            Line::parse("6 3 - 2 -")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::from_integer(1)])
+        );
+        assert_eq!(
+            Line::parse("6 -3 - -2 -")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::from_integer(11)])
+        );
+        assert_eq!(
+            Line::parse("6 +3 - -2 *")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::from_integer(-6)])
+        );
+        assert_eq!(
+            Line::parse("2 4 6 S")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::from_integer(12)])
+        );
+        assert_eq!(
+            Line::parse("3 2 ^").unwrap().1.calc(Stack::new()).unwrap(),
+            Stack(vec![Rational64::from_integer(9)])
+        );
+    }
+    #[test]
+    fn test_op_on_empty_stack() {
+        assert_eq!(
+            Line::parse("+").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+        assert_eq!(
+            Line::parse("1+").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        )
+    }
+
+    #[test]
+    fn test_creating_fraction() {
+        assert_eq!(
+            Line::parse("1 2 /").unwrap().1.calc(Stack::new()).unwrap(),
+            Stack(vec![Rational64::new(1, 2)])
+        );
+        assert_eq!(
+            Line::parse("2 4 /").unwrap().1.calc(Stack::new()).unwrap(),
+            Stack(vec![Rational64::new(1, 2)])
+        )
+    }
+
+    #[test]
+    fn operations_on_fractions() {
+        assert_eq!(
+            Line::parse("1 2 / 1 2 / +")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::from_integer(1)])
+        );
+        assert_eq!(
+            Line::parse("1 2 / 1 2 / *")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::new(1, 4)])
+        );
+        assert_eq!(
+            Line::parse("1 2 / 1 2 / -")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::from_integer(0)])
+        );
+    }
+
+    #[test]
+    fn test_infix_parsing() {
+        assert_eq!(
+            Line::parse_infix("3 + 4"),
+            Ok((
+                "",
+                Line(vec![
+                    Item::Num(Rational64::from_integer(3)),
+                    Item::Num(Rational64::from_integer(4)),
+                    Item::Operator(Operator::Add)
+                ])
+            ))
+        );
+        assert_eq!(
+            Line::parse_infix("2 ^ 3 ^ 2"),
+            Ok((
+                "",
+                Line(vec![
+                    Item::Num(Rational64::from_integer(2)),
+                    Item::Num(Rational64::from_integer(3)),
+                    Item::Num(Rational64::from_integer(2)),
+                    Item::Operator(Operator::Power),
+                    Item::Operator(Operator::Power)
+                ])
+            ))
+        );
+        assert!(Line::parse_infix("(3 + 4").is_err());
+        assert!(Line::parse_infix("3 +").is_err());
+    }
+
+    #[test]
+    fn test_calculating_infix() {
+        assert_eq!(
+            Line::parse_infix("(3 + 4) * 2")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::from_integer(14)])
+        );
+        assert_eq!(
+            Line::parse_infix("3 + 4 * 2")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::from_integer(11)])
+        );
+        assert_eq!(
+            Line::parse_infix("10 - 4 - 3\n")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::from_integer(3)])
+        );
+        assert_eq!(
+            Line::parse_infix("-(1 + 2) / 6")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::new(-1, 2)])
+        );
+    }
+
+    #[test]
+    fn test_infix_unary_minus_and_power() {
+        let eval = |input| Line::parse_infix(input).unwrap().1.calc(Stack::new());
+        assert_eq!(eval("-2^2"), Ok(Stack::from(vec![-4])));
+        assert_eq!(eval("- 2 ^ 2"), Ok(Stack::from(vec![-4])));
+        assert_eq!(eval("0-2^2"), Ok(Stack::from(vec![-4])));
+        assert_eq!(eval("(-2)^2"), Ok(Stack::from(vec![4])));
+        assert_eq!(eval("2^-1"), Ok(Stack(vec![Rational64::new(1, 2)])));
+        assert_eq!(eval("--2^2"), Ok(Stack::from(vec![4])));
+        assert_eq!(eval("3 * -2 + +1"), Ok(Stack::from(vec![-5])));
+        assert_eq!(
+            Line::parse_infix("-9223372036854775808"),
+            Ok((
+                "",
+                Line(vec![Item::Num(Rational64::from_integer(i64::MIN))])
+            ))
+        );
+    }
+
+    #[test]
+    fn test_settings_from_args() {
+        assert_eq!(Settings::from_args(vec![]).unwrap().mode, Mode::Rpn);
+        assert_eq!(
+            Settings::from_args(vec!["--infix".to_string()])
+                .unwrap()
+                .mode,
+            Mode::Infix
+        );
+        assert!(Settings::from_args(vec!["--bogus".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_sign_tokenization() {
+        assert_eq!(
+            Line::parse("3 -5"),
+            Ok((
+                "",
+                Line(vec![
+                    Item::Num(Rational64::from_integer(3)),
+                    Item::Num(Rational64::from_integer(-5)),
+                ])
+            ))
+        );
+        assert_eq!(
+            Line::parse("3 - 5"),
+            Ok((
+                "",
+                Line(vec![
+                    Item::Num(Rational64::from_integer(3)),
+                    Item::Operator(Operator::Subtract),
+                    Item::Num(Rational64::from_integer(5)),
+                ])
+            ))
+        );
+        assert_eq!(
+            Line::parse("3-5"),
+            Ok((
+                "",
+                Line(vec![
+                    Item::Num(Rational64::from_integer(3)),
+                    Item::Num(Rational64::from_integer(-5)),
+                ])
+            ))
+        );
+        assert_eq!(
+            Line::parse("3 +5"),
+            Ok((
+                "",
+                Line(vec![
+                    Item::Num(Rational64::from_integer(3)),
+                    Item::Num(Rational64::from_integer(5)),
+                ])
+            ))
+        );
+        assert_eq!(
+            Line::parse("3 5 -+"),
+            Ok((
+                "",
+                Line(vec![
+                    Item::Num(Rational64::from_integer(3)),
+                    Item::Num(Rational64::from_integer(5)),
+                    Item::Operator(Operator::Subtract),
+                    Item::Operator(Operator::Add),
+                ])
+            ))
+        );
+    }
+
+    #[test]
+    fn test_power_with_fractional_exponent() {
+        assert_eq!(
+            Line::parse("8 1 3 / ^").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("2 -2 ^").unwrap().1.calc(Stack::new()).unwrap(),
+            Stack(vec![Rational64::new(1, 4)])
+        );
+    }
+
+    #[test]
+    fn test_power_overflow() {
+        assert_eq!(
+            Line::parse("2 10 ^").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![1024]))
+        );
+        assert_eq!(
+            Line::parse("2 3 / 3 ^").unwrap().1.calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(8, 27)]))
+        );
+        assert_eq!(
+            Line::parse("-3 3 ^").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![-27]))
+        );
+        assert_eq!(
+            Line::parse("5 0 ^").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![1]))
+        );
+        assert_eq!(
+            Line::parse("2 63 ^").unwrap().1.calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+        assert_eq!(
+            Line::parse("1000 1000000000000 ^")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+        assert_eq!(
+            Line::parse("-1 1000000000001 ^")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![-1]))
+        );
+        assert_eq!(
+            Line::parse("0 -1 ^").unwrap().1.calc(Stack::new()),
+            Err(CalcError::DivideByZero)
+        );
+        assert_eq!(
+            Line::parse("-9223372036854775808 -1 ^")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+        assert_eq!(
+            Line::parse("-9223372036854775807 -1 ^")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(-1, 9223372036854775807)]))
+        );
+    }
+
+    #[test]
+    fn test_panic_becomes_internal_error() {
+        assert_eq!(
+            recover::<()>(|| panic!("boom")),
+            Err(CalcError::Internal("boom".to_string()))
+        );
+        assert_eq!(
+            recover::<()>(|| panic!("{} went wrong", 1 + 1)),
+            Err(CalcError::Internal("2 went wrong".to_string()))
+        );
+        assert_eq!(recover(|| Ok(3)), Ok(3));
+        assert_eq!(
+            recover::<()>(|| Err(CalcError::MathError)),
+            Err(CalcError::MathError)
+        );
+        // Panics outside `recover` are reported as usual again.
+        assert!(!RECOVERING.with(Cell::get));
+    }
+
+    #[test]
+    fn test_session_survives_failed_lines() {
+        // This used to panic inside `par`, which `assert` ran unprotected.
+        let mut calculator = Calculator::default();
+        calculator.run("5").unwrap();
+        let report = calculator
+            .run("assert -9223372036854775808 1 par")
+            .unwrap_err();
+        assert!(report.starts_with("error[E_OVERFLOW]"), "{report}");
+        let report = calculator.run("-9223372036854775808 1 par").unwrap_err();
+        assert!(report.contains("[E_OVERFLOW]"), "{report}");
+        assert_eq!(calculator.stack, Stack::from(vec![5]));
+        assert_eq!(
+            calculator.run("1 +"),
+            Ok("Stack: 6, Result: 6\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_sum_overflow() {
+        let mut calculator = Calculator::default();
+        calculator.run("5").unwrap();
+        assert_eq!(
+            calculator.eval("p 9223372036854775807 1 S"),
+            Err(CalcError::Overflow)
+        );
+        assert_eq!(calculator.stack, Stack::from(vec![5]));
+        assert_eq!(
+            calculator.run("1 +"),
+            Ok("Stack: 6, Result: 6\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let codes = [
+            (CalcError::NotEnoughItemsInStack, "E_STACK"),
+            (CalcError::MathError, "E_MATH"),
+            (CalcError::InexactResult, "E_INEXACT"),
+            (CalcError::UnknownWord("x".to_string()), "E_WORD"),
+            (CalcError::Overflow, "E_OVERFLOW"),
+            (CalcError::StackOverflow, "E_DEPTH"),
+            (CalcError::MacroArity("x".to_string()), "E_ARITY"),
+            (CalcError::DenominatorTooLarge(7), "E_DENOM"),
+            (CalcError::DivideByZero, "E_DIV0"),
+            (CalcError::InputTooLong(1), "E_LENGTH"),
+            (CalcError::ParseError(String::new()), "E_PARSE"),
+            (CalcError::Internal(String::new()), "E_INTERNAL"),
+        ];
+        for (error, code) in codes {
+            assert_eq!(error.code(), code);
+        }
+        assert_eq!(
+            CalcError::DivideByZero.report(),
+            "error[E_DIV0]: Division by zero!"
+        );
+        let mut calculator = Calculator::default();
+        assert_eq!(
+            calculator.run("1 0 divmod"),
+            Err("Stack: , error[E_DIV0]: Division by zero!\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_stack_conversions() {
+        let stack = Stack::from(vec![3, 6]);
+        assert_eq!(
+            Line::parse("+").unwrap().1.calc(stack).unwrap(),
+            Stack::from(vec![9])
+        );
+        assert_eq!(
+            Vec::from(Stack::from(vec![Rational64::new(1, 2)])),
+            vec![Rational64::new(1, 2)]
+        );
+    }
+
+    #[test]
+    fn test_stack_display_order() {
+        let stack = Stack::from(vec![1, 2, 3]);
+        assert_eq!(stack.display(Order::BottomFirst).to_string(), "1 2 3");
+        assert_eq!(stack.display(Order::TopFirst).to_string(), "3 2 1");
+        assert_eq!(stack.to_string(), "1 2 3");
+        assert_eq!(stack, Stack::from(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_stack_display() {
+        assert_eq!(Stack::from(vec![1, 2, 3]).to_string(), "1 2 3");
+        assert_eq!(Stack::new().to_string(), "");
+        assert_eq!(
+            Stack::from(vec![
+                Rational64::new(1, 2),
+                Rational64::new(3, 2),
+                Rational64::new(-7, 3)
+            ])
+            .to_string(),
+            "1/2 1+1/2 -2+-1/3"
+        );
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn test_seed_resolution() {
+        assert_eq!(resolve_seed(None, None).unwrap(), None);
+        assert_eq!(
+            resolve_seed(None, Some("42".to_string())).unwrap(),
+            Some(42)
+        );
+        assert_eq!(
+            resolve_seed(Some(7), Some("42".to_string())).unwrap(),
+            Some(7)
+        );
+        assert_eq!(resolve_seed(Some(7), None).unwrap(), Some(7));
+        assert!(resolve_seed(None, Some("lots".to_string())).is_err());
+        assert_eq!(
+            resolve_seed(Some(7), Some("lots".to_string())).unwrap(),
+            Some(7)
+        );
+    }
+
+    #[test]
+    fn test_prompt_resolution() {
+        assert_eq!(resolve_prompt(None, None), "> ");
+        assert_eq!(resolve_prompt(None, Some("calc> ".to_string())), "calc> ");
+        assert_eq!(
+            resolve_prompt(Some("$ ".to_string()), Some("calc> ".to_string())),
+            "$ "
+        );
+        assert_eq!(resolve_prompt(Some(String::new()), None), "");
+        assert_eq!(
+            Settings::from_args(vec!["--prompt".to_string(), String::new()])
+                .unwrap()
+                .prompt,
+            Some(String::new())
+        );
+        assert!(Settings::from_args(vec!["--prompt".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_stack_dump() {
+        let stack = Stack::from(vec![
+            Rational64::from_integer(3),
+            Rational64::from_integer(7),
+            Rational64::new(1, 2),
+        ]);
+        assert_eq!(stack.dump(), "0: 1/2\n1: 7\n2: 3\n");
+        assert_eq!(Stack::new().dump(), "");
+    }
+
+    #[test]
+    fn test_exponentials() {
+        assert_eq!(
+            Line::parse("0 exp").unwrap().1.calc(Stack::new()).unwrap(),
+            Stack::from(vec![1])
+        );
+        assert_eq!(
+            Line::parse("2 exp10")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack::from(vec![100])
+        );
+        let e = Line::parse("1 exp").unwrap().1.calc(Stack::new()).unwrap();
+        assert!((to_float(e.last().unwrap()) - std::f64::consts::E).abs() < 1e-9);
+        assert_eq!(
+            Line::parse("1000 exp").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_between() {
+        assert_eq!(
+            Line::parse("5 0 10 between?")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack::from(vec![1])
+        );
+        assert_eq!(
+            Line::parse("15 0 10 between?")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack::from(vec![0])
+        );
+        assert_eq!(
+            Line::parse("5 10 0 between?").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("0 10 between?").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+    }
+
+    #[test]
+    fn test_square_root() {
+        assert_eq!(
+            Line::parse("9 4 / q")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::new(3, 2)])
+        );
+        let root = Line::parse("2 q").unwrap().1.calc(Stack::new()).unwrap();
+        assert!((to_float(root.last().unwrap()) - std::f64::consts::SQRT_2).abs() < 1e-9);
+        assert_eq!(
+            Line::parse("-4 q").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_exact_mode() {
+        let mut exact = Calculator::new(Settings {
+            exact: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            Line::parse("2 q")
+                .unwrap()
+                .1
+                .calc_with(Stack::new(), &mut exact),
+            Err(CalcError::InexactResult)
+        );
+        assert_eq!(
+            Line::parse("4 q")
+                .unwrap()
+                .1
+                .calc_with(Stack::new(), &mut exact),
+            Ok(Stack::from(vec![2]))
+        );
+        assert_eq!(
+            Line::parse("1 exp")
+                .unwrap()
+                .1
+                .calc_with(Stack::new(), &mut exact),
+            Err(CalcError::InexactResult)
+        );
+        assert_eq!(
+            Line::parse("0 exp 2 exp10")
+                .unwrap()
+                .1
+                .calc_with(Stack::new(), &mut exact),
+            Ok(Stack::from(vec![1, 100]))
+        );
+    }
+
+    #[test]
+    fn test_word_parsing() {
+        assert_eq!(
+            Line::parse("cube c"),
+            Ok((
+                "",
+                Line(vec![
+                    Item::Word("cube".to_string()),
+                    Item::Operator(Operator::Clear)
+                ])
+            ))
+        );
+        assert_eq!(
+            Line::parse("exp10 exp1"),
+            Ok((
+                "",
+                Line(vec![
+                    Item::Operator(Operator::Exp10),
+                    Item::Word("exp1".to_string())
+                ])
+            ))
+        );
+        assert_eq!(
+            Line::parse("2 foo").unwrap().1.calc(Stack::new()),
+            Err(CalcError::UnknownWord("foo".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_macros() {
+        let mut calculator = Calculator::default();
+        assert_eq!(calculator.run("def add3 3 +"), Ok(String::new()));
+        assert_eq!(calculator.run("def add6 add3 add3"), Ok(String::new()));
+        assert_eq!(calculator.run("def add3 100 +"), Ok(String::new()));
+        calculator.run("1 add6").unwrap();
+        assert_eq!(calculator.stack, Stack::from(vec![7]));
+        assert!(calculator.run("def c 1").is_err());
+        assert!(calculator.run("def bad nope").is_err());
+        assert!(calculator.run("unknown").is_err());
+        assert_eq!(calculator.stack, Stack::from(vec![7]));
+    }
+
+    #[test]
+    fn test_variables() {
+        let mut calculator = Calculator::default();
+        calculator.run("1 2 /").unwrap();
+        assert_eq!(calculator.run("store half"), Ok(String::new()));
+        calculator.run("c 42").unwrap();
+        calculator.run("store answer").unwrap();
+        assert_eq!(calculator.stack, Stack::from(vec![42]));
+        assert_eq!(
+            calculator.run("vars"),
+            Ok("answer = 42\nhalf = 1/2\n".to_string())
+        );
+        calculator.run("c half answer *").unwrap();
+        assert_eq!(calculator.stack, Stack::from(vec![21]));
+
+        assert_eq!(calculator.run("unset half"), Ok(String::new()));
+        assert_eq!(calculator.run("vars"), Ok("answer = 42\n".to_string()));
+        assert_eq!(
+            calculator.run("unset half"),
+            Err("No variable half\n".to_string())
+        );
+        assert!(calculator.run("half").is_err());
+        assert_eq!(calculator.eval("answer"), Ok(Some(Rational64::from(42))));
+
+        assert!(calculator.run("store +").is_err());
+        calculator.run("c").unwrap();
+        assert_eq!(
+            calculator.run("store empty"),
+            Err("Nothing to store\n".to_string())
+        );
+        calculator.run("reset").unwrap();
+        assert_eq!(calculator.run("vars"), Ok(String::new()));
+    }
+
+    #[test]
+    fn test_list_and_delete_macros() {
+        let mut calculator = Calculator::default();
+        assert_eq!(calculator.run("defs"), Ok(String::new()));
+        calculator.run("def sq dup *").unwrap();
+        calculator.run("def add3 3   +").unwrap();
+        calculator.run("def add6 add3 add3").unwrap();
+        assert_eq!(
+            calculator.run("defs"),
+            Ok("add3 = 3 +\nadd6 = 3 + 3 +\nsq = dup *\n".to_string())
+        );
+        assert_eq!(calculator.run("undef add3"), Ok(String::new()));
+        assert_eq!(
+            calculator.run("defs"),
+            Ok("add6 = 3 + 3 +\nsq = dup *\n".to_string())
+        );
+        assert_eq!(
+            calculator.run("undef add3"),
+            Err("No macro add3\n".to_string())
+        );
+        assert!(calculator.run("1 add3").is_err());
+        assert_eq!(calculator.eval("1 add6 sq"), Ok(Some(Rational64::from(49))));
+    }
+
+    #[test]
+    fn test_calculator_commands() {
+        let mut calculator = Calculator::default();
+        calculator.run("mode infix").unwrap();
+        assert_eq!(
+            calculator.run("1 + 2 * 3\n"),
+            Ok("Stack: 7, Result: 7\n".to_string())
+        );
+        calculator.run("mode rpn").unwrap();
+        assert_eq!(
+            calculator.run("1 +"),
+            Ok("Stack: 8, Result: 8\n".to_string())
+        );
+        assert_eq!(
+            calculator.run("+"),
+            Err("Stack: 8, error[E_STACK]: Not enough items in stack!\n".to_string())
+        );
+        assert_eq!(calculator.run("dump"), Ok("0: 8\n".to_string()));
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn test_rand() {
+        let settings = || Settings::from_args(vec!["--seed".to_string(), "42".to_string()]);
+        let line = Line::parse("rand rand rand").unwrap().1;
+        let first = line
+            .calc_with(Stack::new(), &mut Calculator::new(settings().unwrap()))
+            .unwrap();
+        let second = line
+            .calc_with(Stack::new(), &mut Calculator::new(settings().unwrap()))
+            .unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.0.len(), 3);
+        assert!(first.0.iter().all(|x| {
+            *x >= Rational64::from(0)
+                && *x < Rational64::from(1)
+                && (x * Rational64::from(1 << 32)).is_integer()
+        }));
+        assert_ne!(first.0[0], first.0[1]);
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn test_randint() {
+        let seeded = || {
+            Calculator::new(
+                Settings::from_args(vec!["--seed".to_string(), "7".to_string()]).unwrap(),
+            )
+        };
+        let line = Line::parse("-3 3 randint 1 6 randint 1 6 randint 5 5 randint")
+            .unwrap()
+            .1;
+        let first = line.calc_with(Stack::new(), &mut seeded()).unwrap();
+        assert_eq!(first, line.calc_with(Stack::new(), &mut seeded()).unwrap());
+        assert!(first.0[0] >= Rational64::from(-3) && first.0[0] <= Rational64::from(3));
+        assert!(first.0[1..3]
+            .iter()
+            .all(|x| *x >= Rational64::from(1) && *x <= Rational64::from(6)));
+        assert_eq!(first.0[3], Rational64::from(5));
+
+        let mut calculator = seeded();
+        for _ in 0..1000 {
+            let n = calculator.rng.next_in_range(i64::MIN, i64::MAX);
+            assert!((i64::MIN..=i64::MAX).contains(&n));
+            assert!((0..=1).contains(&calculator.rng.next_in_range(0, 1)));
+        }
+        assert_eq!(
+            Line::parse("6 1 randint").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("1 1 2 / randint").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_sum_of_squares() {
+        assert_eq!(
+            Line::parse("1 2 3 sumsq")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack::from(vec![14])
+        );
+        assert_eq!(
+            Line::parse("sumsq").unwrap().1.calc(Stack::new()).unwrap(),
+            Stack::from(vec![0])
+        );
+        assert_eq!(
+            Line::parse("4000000000 sumsq")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_variance_and_standard_deviation() {
+        assert_eq!(
+            Line::parse("2 4 4 4 5 5 7 9 variance")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack::from(vec![4])
+        );
+        let stddev = Line::parse("2 4 4 4 5 5 7 9 stddev")
+            .unwrap()
+            .1
+            .calc(Stack::new())
+            .unwrap();
+        assert!((to_float(stddev.last().unwrap()) - 2.0).abs() < 1e-9);
+        assert_eq!(
+            Line::parse("1 2 variance").unwrap().1.calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(1, 4)]))
+        );
+        assert_eq!(
+            Line::parse("variance").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+        assert_eq!(
+            Line::parse("1 4611686018427387903 / 0 0 variance")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+        assert_eq!(
+            Line::parse("stddev").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+    }
+
+    #[test]
+    fn test_is_sorted() {
+        assert_eq!(
+            Line::parse("1 2 3 sorted?").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![1, 2, 3, 1]))
+        );
+        assert_eq!(
+            Line::parse("3 1 2 sorted?").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![3, 1, 2, 0]))
+        );
+        assert_eq!(
+            Line::parse("2 2 sorted?").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![2, 2, 1]))
+        );
+        assert_eq!(
+            Line::parse("sorted?").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![1]))
+        );
+        assert_eq!(
+            Line::parse("5 sorted?").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![5, 1]))
+        );
+    }
+
+    #[test]
+    fn test_count() {
+        assert_eq!(
+            Line::parse("2 2 3 2 count").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![2, 2, 3, 2]))
+        );
+        assert_eq!(
+            Line::parse("1 2 / 2 4 / 1 3 / 1 2 / count")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![
+                Rational64::new(1, 2),
+                Rational64::new(1, 2),
+                Rational64::new(1, 3),
+                Rational64::from(2),
+            ]))
+        );
+        assert_eq!(
+            Line::parse("7 count").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![0]))
+        );
+        assert_eq!(
+            Line::parse("count").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+    }
+
+    #[test]
+    fn test_stack_extrema() {
+        assert_eq!(
+            Line::parse("3 7 1 maxall").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![3, 7, 1, 7]))
+        );
+        assert_eq!(
+            Line::parse("3 7 1 minall").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![3, 7, 1, 1]))
+        );
+        assert_eq!(
+            Line::parse("1 2 / 1 3 / minall")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![
+                Rational64::new(1, 2),
+                Rational64::new(1, 3),
+                Rational64::new(1, 3),
+            ]))
+        );
+        assert_eq!(
+            Line::parse("maxall").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+        assert_eq!(
+            Line::parse("minall").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+    }
+
+    #[test]
+    fn test_sort() {
+        assert_eq!(
+            Line::parse("3 1 2 sort").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![1, 2, 3]))
+        );
+        assert_eq!(
+            Line::parse("3 1 2 sortdesc").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![3, 2, 1]))
+        );
+        assert_eq!(
+            Line::parse("1 2 / 1 3 / -1 sort")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![
+                Rational64::from(-1),
+                Rational64::new(1, 3),
+                Rational64::new(1, 2),
+            ]))
+        );
+        assert_eq!(
+            Line::parse("sort").unwrap().1.calc(Stack::new()),
+            Ok(Stack::new())
+        );
+        assert_eq!(
+            Line::parse("7 sortdesc").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![7]))
+        );
+    }
+
+    #[test]
+    fn test_insert_at() {
+        assert_eq!(
+            Line::parse("99 1 insert")
+                .unwrap()
+                .1
+                .calc(Stack::from(vec![1, 2, 3])),
+            Ok(Stack::from(vec![1, 2, 99, 3]))
+        );
+        assert_eq!(
+            Line::parse("99 0 insert")
+                .unwrap()
+                .1
+                .calc(Stack::from(vec![1, 2, 3])),
+            Ok(Stack::from(vec![1, 2, 3, 99]))
+        );
+        assert_eq!(
+            Line::parse("99 3 insert")
+                .unwrap()
+                .1
+                .calc(Stack::from(vec![1, 2, 3])),
+            Ok(Stack::from(vec![99, 1, 2, 3]))
+        );
+        assert_eq!(
+            Line::parse("99 4 insert")
+                .unwrap()
+                .1
+                .calc(Stack::from(vec![1, 2, 3])),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("99 -1 insert")
+                .unwrap()
+                .1
+                .calc(Stack::from(vec![1, 2, 3])),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("99 1 2 / insert")
+                .unwrap()
+                .1
+                .calc(Stack::from(vec![1, 2, 3])),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("1 insert").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+    }
+
+    #[test]
+    fn test_remove_at() {
+        assert_eq!(
+            Line::parse("1 remove")
+                .unwrap()
+                .1
+                .calc(Stack::from(vec![1, 2, 3])),
+            Ok(Stack::from(vec![1, 3]))
+        );
+        assert_eq!(
+            Line::parse("0 remove")
+                .unwrap()
+                .1
+                .calc(Stack::from(vec![1, 2, 3])),
+            Ok(Stack::from(vec![1, 2]))
+        );
+        assert_eq!(
+            Line::parse("2 remove")
+                .unwrap()
+                .1
+                .calc(Stack::from(vec![1, 2, 3])),
+            Ok(Stack::from(vec![2, 3]))
+        );
+        assert_eq!(
+            Line::parse("3 remove")
+                .unwrap()
+                .1
+                .calc(Stack::from(vec![1, 2, 3])),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("-1 remove")
+                .unwrap()
+                .1
+                .calc(Stack::from(vec![1, 2, 3])),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("1 2 / remove")
+                .unwrap()
+                .1
+                .calc(Stack::from(vec![1, 2, 3])),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("0 remove").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(
+            Line::parse("3 1 2 median").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![2]))
+        );
+        assert_eq!(
+            Line::parse("1 2 3 4 median").unwrap().1.calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(5, 2)]))
+        );
+        assert_eq!(
+            Line::parse("7 median").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![7]))
+        );
+        assert_eq!(
+            Line::parse("median").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+        assert_eq!(
+            Line::parse("9223372036854775807 9223372036854775807 median")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![9223372036854775807]))
+        );
+        assert_eq!(
+            Line::parse("-9223372036854775808 9223372036854775807 median")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(-1, 2)]))
+        );
+        assert_eq!(
+            Line::parse("1 9223372036854775807 / 0 median")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_mode() {
+        assert_eq!(
+            Line::parse("1 2 2 3 mode!").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![2]))
+        );
+        assert_eq!(
+            Line::parse("2 2 1 1 mode!").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![1, 2]))
+        );
+        assert_eq!(
+            Line::parse("1 2 / 2 4 / 3 mode!")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(1, 2)]))
+        );
+        assert_eq!(
+            Line::parse("mode!").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+    }
+
+    #[test]
+    fn test_abs_diff() {
+        assert_eq!(
+            Line::parse("3 7 absdiff").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![4]))
+        );
+        assert_eq!(
+            Line::parse("7 3 absdiff").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![4]))
+        );
+        assert_eq!(
+            Line::parse("1 2 / 1 3 / absdiff")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(1, 6)]))
+        );
+        assert_eq!(
+            Line::parse("-9223372036854775808 0 absdiff")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+        assert_eq!(
+            Line::parse("1 absdiff").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+    }
+
+    #[test]
+    fn test_approx_eq() {
+        assert_eq!(
+            Line::parse("2 q 1414 1000 / 1 1000 / approxeq")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![1]))
+        );
+        assert_eq!(
+            Line::parse("2 q 1414 1000 / 1 10000 / approxeq")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![0]))
+        );
+        assert_eq!(
+            Line::parse("3 5 2 approxeq").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![1]))
+        );
+        assert_eq!(
+            Line::parse("1 1 0 approxeq").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![1]))
+        );
+        assert_eq!(
+            Line::parse("1 1 -1 approxeq").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_falling_factorial() {
+        assert_eq!(
+            Line::parse("5 3 ff").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![60]))
+        );
+        assert_eq!(
+            Line::parse("5 0 ff").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![1]))
+        );
+        assert_eq!(
+            Line::parse("5 5 ff").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![120]))
+        );
+        assert_eq!(
+            Line::parse("3 1000000000000 ff")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![0]))
+        );
+        assert_eq!(
+            Line::parse("-5 2 ff").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("5 -1 ff").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("5 1 2 / ff").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("100 20 ff").unwrap().1.calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_dot() {
+        assert_eq!(
+            Line::parse("1 2 3 4 dot").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![11]))
+        );
+        assert_eq!(
+            Line::parse("1 2 / 4 dot").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![2]))
+        );
+        assert_eq!(
+            Line::parse("dot").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![0]))
+        );
+        assert_eq!(
+            Line::parse("1 2 3 dot").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("4000000000 4000000000 dot")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_scale_all() {
+        assert_eq!(
+            Line::parse("1 2 3 2 scaleall")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![2, 4, 6]))
+        );
+        assert_eq!(
+            Line::parse("4 6 1 2 / scaleall")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![2, 3]))
+        );
+        assert_eq!(
+            Line::parse("3 scaleall").unwrap().1.calc(Stack::new()),
+            Ok(Stack::new())
+        );
+        assert_eq!(
+            Line::parse("scaleall").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+        assert_eq!(
+            Line::parse("1 9223372036854775807 2 scaleall")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_offset_all() {
+        assert_eq!(
+            Line::parse("1 2 3 10 addall").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![11, 12, 13]))
+        );
+        assert_eq!(
+            Line::parse("5 10 2 scaleall -1 addall")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![9, 19]))
+        );
+        assert_eq!(
+            Line::parse("3 addall").unwrap().1.calc(Stack::new()),
+            Ok(Stack::new())
+        );
+        assert_eq!(
+            Line::parse("9223372036854775807 1 addall")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+        // Not to be confused with a word that merely starts the same way.
+        assert_eq!(
+            Line::parse("addall2"),
+            Ok(("", Line(vec![Item::Word("addall2".to_string())])))
+        );
+    }
+
+    #[test]
+    fn test_cum_max() {
+        assert_eq!(
+            Line::parse("3 1 4 1 5 cummax")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![3, 3, 4, 4, 5]))
+        );
+        assert_eq!(
+            Line::parse("1 2 / -1 1 3 / cummax")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(1, 2); 3]))
+        );
+        assert_eq!(
+            Line::parse("cummax").unwrap().1.calc(Stack::new()),
+            Ok(Stack::new())
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(
+            Line::parse("1 4 9 16 diff").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![3, 5, 7]))
+        );
+        assert_eq!(
+            Line::parse("5 diff").unwrap().1.calc(Stack::new()),
+            Ok(Stack::new())
+        );
+        assert_eq!(
+            Line::parse("diff").unwrap().1.calc(Stack::new()),
+            Ok(Stack::new())
+        );
+        assert_eq!(
+            Line::parse("-9223372036854775807 1 diff")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_find() {
+        assert_eq!(
+            Line::parse("10 20 30 20 find")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![10, 20, 30, 1]))
+        );
+        assert_eq!(
+            Line::parse("5 7 5 5 find").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![5, 7, 5, 0]))
+        );
+        assert_eq!(
+            Line::parse("10 20 99 find").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![10, 20, -1]))
+        );
+        assert_eq!(
+            Line::parse("find").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+    }
+
+    #[test]
+    fn test_dedup() {
+        assert_eq!(
+            Line::parse("1 1 2 3 3 3 dedup")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![1, 2, 3]))
+        );
+        assert_eq!(
+            Line::parse("1 2 1 1 2 / 2 4 / dedup")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![
+                Rational64::from(1),
+                Rational64::from(2),
+                Rational64::from(1),
+                Rational64::new(1, 2),
+            ]))
+        );
+        assert_eq!(
+            Line::parse("dedup").unwrap().1.calc(Stack::new()),
+            Ok(Stack::new())
+        );
+        assert_eq!(
+            Line::parse("5 dedup").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![5]))
+        );
+    }
+
+    #[test]
+    fn test_unique() {
+        assert_eq!(
+            Line::parse("1 2 1 3 2 uniq").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![1, 2, 3]))
+        );
+        assert_eq!(
+            Line::parse("2 4 / 3 1 2 / uniq")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(1, 2), Rational64::from(3)]))
+        );
+        assert_eq!(
+            Line::parse("uniq").unwrap().1.calc(Stack::new()),
+            Ok(Stack::new())
+        );
+    }
+
+    #[test]
+    fn test_root() {
+        assert_eq!(
+            Line::parse("27 3 root").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![3]))
+        );
+        assert_eq!(
+            Line::parse("1 8 / 3 root").unwrap().1.calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(1, 2)]))
+        );
+        assert_eq!(
+            Line::parse("-32 5 root").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![-2]))
+        );
+        let cbrt = Line::parse("2 3 root")
+            .unwrap()
+            .1
+            .calc(Stack::new())
+            .unwrap();
+        assert!((to_float(cbrt.last().unwrap()) - 2f64.cbrt()).abs() < 1e-9);
+        assert_eq!(
+            Line::parse("-4 2 root").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("4 0 root").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("4 1 2 / root").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+        let mut calculator = Calculator::new(Settings {
+            exact: true,
+            ..Settings::default()
+        });
+        assert_eq!(
+            Line::parse("2 3 root")
+                .unwrap()
+                .1
+                .calc_with(Stack::new(), &mut calculator),
+            Err(CalcError::InexactResult)
+        );
+    }
+
+    #[test]
+    fn test_swap_at() {
+        let stack = || Stack::from(vec![1, 2, 3, 4]);
+        assert_eq!(
+            Line::parse("0 3 swapat").unwrap().1.calc(stack()),
+            Ok(Stack::from(vec![4, 2, 3, 1]))
+        );
+        assert_eq!(
+            Line::parse("2 1 swapat").unwrap().1.calc(stack()),
+            Ok(Stack::from(vec![1, 3, 2, 4]))
+        );
+        assert_eq!(
+            Line::parse("1 1 swapat").unwrap().1.calc(stack()),
+            Ok(stack())
+        );
+        assert_eq!(
+            Line::parse("0 4 swapat").unwrap().1.calc(stack()),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("-1 0 swapat").unwrap().1.calc(stack()),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("0 1 2 / swapat").unwrap().1.calc(stack()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_rotate_stack() {
+        assert_eq!(
+            Line::parse("1 2 3 4 1 rotate")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![2, 3, 4, 1]))
+        );
+        assert_eq!(
+            Line::parse("1 2 3 4 -1 rotate")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![4, 1, 2, 3]))
+        );
+        assert_eq!(
+            Line::parse("1 2 3 4 6 rotate")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![3, 4, 1, 2]))
+        );
+        assert_eq!(
+            Line::parse("5 rotate").unwrap().1.calc(Stack::new()),
+            Ok(Stack::new())
+        );
+        assert_eq!(
+            Line::parse("1 2 1 2 / rotate")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_range() {
+        assert_eq!(
+            Line::parse("1 5 1 range").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![1, 2, 3, 4, 5]))
+        );
+        assert_eq!(
+            Line::parse("2 10 2 range").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![2, 4, 6, 8, 10]))
+        );
+        assert_eq!(
+            Line::parse("1 8 3 range").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![1, 4, 7]))
+        );
+        assert_eq!(
+            Line::parse("3 1 -1 range").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![3, 2, 1]))
+        );
+        assert_eq!(
+            Line::parse("0 1 1 2 / range").unwrap().1.calc(Stack::new()),
+            Ok(Stack(vec![
+                Rational64::from(0),
+                Rational64::new(1, 2),
+                Rational64::from(1),
+            ]))
+        );
+        assert_eq!(
+            Line::parse("4 4 1 range").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![4]))
+        );
+        assert_eq!(
+            Line::parse("1 5 0 range").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("5 1 1 range").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("1 5 -1 range").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("1 9223372036854775807 1 range")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::StackOverflow)
+        );
+    }
+
+    #[test]
+    fn test_weighted_sum() {
+        assert_eq!(
+            Line::parse("2 3 4 5 wsum").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![26]))
+        );
+        assert_eq!(
+            Line::parse("1 1 4 / 3 3 4 / wsum")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(5, 2)]))
+        );
+        assert_eq!(
+            Line::parse("wsum").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![0]))
+        );
+        assert_eq!(
+            Line::parse("2 3 4 wsum").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_normalize_sum() {
+        assert_eq!(
+            Line::parse("1 2 1 prob").unwrap().1.calc(Stack::new()),
+            Ok(Stack(vec![
+                Rational64::new(1, 4),
+                Rational64::new(1, 2),
+                Rational64::new(1, 4),
+            ]))
+        );
+        assert_eq!(
+            Line::parse("3 -1 prob").unwrap().1.calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(3, 2), Rational64::new(-1, 2)]))
+        );
+        assert_eq!(
+            Line::parse("prob").unwrap().1.calc(Stack::new()),
+            Ok(Stack::new())
+        );
+        assert_eq!(
+            Line::parse("1 -1 prob").unwrap().1.calc(Stack::new()),
+            Err(CalcError::DivideByZero)
+        );
+    }
+
+    #[test]
+    fn test_poly_val() {
+        assert_eq!(
+            Line::parse("1 -3 2 5 polyval")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![12]))
+        );
+        assert_eq!(
+            Line::parse("1 -3 2 1 polyval")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![0]))
+        );
+        assert_eq!(
+            Line::parse("4 1 2 / 2 polyval")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(17, 2)]))
+        );
+        assert_eq!(
+            Line::parse("7 polyval").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![0]))
+        );
+        assert_eq!(
+            Line::parse("polyval").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+        assert_eq!(
+            Line::parse("1 0 0 0 10000000 polyval")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_digits() {
+        assert_eq!(
+            Line::parse("12345 digits 0 digits -42 digits")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![5, 1, 2]))
+        );
+        assert_eq!(
+            Line::parse("-9223372036854775807 1 - digits")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![19]))
+        );
+        assert_eq!(
+            Line::parse("1 2 / digits").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_reverse_digits() {
+        assert_eq!(
+            Line::parse("123 rdigits -120 rdigits 0 rdigits")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![321, -21, 0]))
+        );
+        assert_eq!(
+            Line::parse("1999999999999999999 rdigits")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+        assert_eq!(
+            Line::parse("3 2 / rdigits").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_last_digit() {
+        assert_eq!(
+            Line::parse("1234 lastdigit -7 lastdigit 0 lastdigit")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![4, 7, 0]))
+        );
+        assert_eq!(
+            Line::parse("7 2 / lastdigit").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_is_palindrome() {
+        assert_eq!(
+            Line::parse("121 pal? 123 pal? 7 pal? 0 pal? -1221 pal? 10 pal?")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![1, 0, 1, 1, 1, 0]))
+        );
+        assert_eq!(
+            Line::parse("1 2 / pal?").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_digit_sum() {
+        assert_eq!(
+            Line::parse("12345 dsum 12345 droot -99 dsum -99 droot 0 droot")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![15, 6, 18, 9, 0]))
+        );
+        for line in ["1 2 / dsum", "1 2 / droot"] {
+            assert_eq!(
+                Line::parse(line).unwrap().1.calc(Stack::new()),
+                Err(CalcError::MathError)
+            );
+        }
+    }
+
+    #[test]
+    fn test_totient() {
+        assert_eq!(
+            Line::parse("9 phi 10 phi 7 phi 1 phi 36 phi")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![6, 4, 6, 1, 12]))
+        );
+        assert_eq!(prime_factors(360), vec![(2, 3), (3, 2), (5, 1)]);
+        assert_eq!(prime_factors(1), vec![]);
+        assert_eq!(
+            prime_factors(9223372021822390277),
+            vec![(2147483647, 1), (4294967291, 1)]
+        );
+        assert_eq!(prime_factors(4611686014132420609), vec![(2147483647, 2)]);
+        // Both of these took seconds by trial division.
+        assert_eq!(
+            Line::parse("9223372036854775783 phi 9223372036854775807 phi")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![9223372036854775782, 7713001620195508224]))
+        );
+        for line in ["0 phi", "-4 phi", "1 2 / phi"] {
+            assert_eq!(
+                Line::parse(line).unwrap().1.calc(Stack::new()),
+                Err(CalcError::MathError)
+            );
+        }
+    }
+
+    #[test]
+    fn test_next_prime() {
+        assert_eq!(
+            Line::parse("14 nextprime 17 nextprime 0 nextprime -5 nextprime 90 nextprime")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![17, 17, 2, 2, 97]))
+        );
+        assert!(is_prime(2) && is_prime(9973) && !is_prime(1) && !is_prime(9));
+        // A Carmichael number, and a strong pseudoprime to bases 2 to 7.
+        assert!(!is_prime(561) && !is_prime(3215031751));
+        assert_eq!(
+            Line::parse("9223372036854775780 nextprime")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![9223372036854775783]))
+        );
+        assert_eq!(
+            Line::parse("9223372036854775784 nextprime")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+        assert_eq!(
+            Line::parse("1 2 / nextprime").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_coprime() {
+        assert_eq!(
+            Line::parse("8 15 coprime? 8 12 coprime? -3 4 coprime? 1 0 coprime? 0 0 coprime?")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![1, 0, 1, 1, 0]))
+        );
+        assert_eq!(
+            Line::parse("1 2 / 3 coprime?")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_mod_inverse() {
+        assert_eq!(
+            Line::parse("3 11 modinv 10 17 modinv 14 11 modinv 5 1 modinv")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![4, 12, 4, 0]))
+        );
+        assert_eq!(mod_inverse(2, i64::MAX), Ok(i64::MAX / 2 + 1));
+        for line in ["2 4 modinv", "0 5 modinv", "3 -11 modinv", "1 2 / 3 modinv"] {
+            assert_eq!(
+                Line::parse(line).unwrap().1.calc(Stack::new()),
+                Err(CalcError::MathError)
+            );
+        }
+    }
+
+    #[test]
+    fn test_valuation() {
+        assert_eq!(
+            Line::parse("8 2 val 12 2 val 7 2 val -250 5 val")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![3, 2, 0, 3]))
+        );
+        assert_eq!(
+            Line::parse("-9223372036854775807 1 - 2 val")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![63]))
+        );
+        for line in [
+            "0 2 val",
+            "8 1 val",
+            "8 -2 val",
+            "1 2 / 2 val",
+            "8 5 2 / val",
+        ] {
+            assert_eq!(
+                Line::parse(line).unwrap().1.calc(Stack::new()),
+                Err(CalcError::MathError)
+            );
+        }
+    }
+
+    #[test]
+    fn test_figurate_numbers() {
+        assert_eq!(
+            Line::parse("5 tri 0 tri 4 pent 0 pent 1 pent")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![15, 0, 22, 0, 1]))
+        );
+        assert_eq!(
+            Line::parse("4294967295 tri").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![9223372034707292160]))
+        );
+        assert_eq!(
+            Line::parse("9223372036854775807 tri")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+        for line in ["-1 tri", "1 2 / tri", "-1 pent"] {
+            assert_eq!(
+                Line::parse(line).unwrap().1.calc(Stack::new()),
+                Err(CalcError::MathError)
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_power_of_two() {
+        assert_eq!(
+            Line::parse("16 pow2? 18 pow2? 1 pow2? 0 pow2? -8 pow2? 4611686018427387904 pow2?")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![1, 0, 1, 0, 0, 1]))
+        );
+        assert_eq!(
+            Line::parse("1 2 / pow2?").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_pop_count() {
+        assert_eq!(
+            Line::parse("7 popcount 8 popcount 0 popcount 9223372036854775807 popcount")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![3, 1, 0, 63]))
+        );
+        for line in ["-1 popcount", "1 2 / popcount"] {
+            assert_eq!(
+                Line::parse(line).unwrap().1.calc(Stack::new()),
+                Err(CalcError::MathError)
+            );
+        }
+    }
+
+    #[test]
+    fn test_euclid() {
+        assert_eq!(
+            Line::parse("48 18 euclid").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![6, 3]))
+        );
+        assert_eq!(
+            Line::parse("18 48 euclid").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![6, 4]))
+        );
+        assert_eq!(
+            Line::parse("-12 0 euclid").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![12, 0]))
+        );
+        assert_eq!(
+            Line::parse("1 2 / 3 euclid").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("3 euclid").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+    }
+
+    #[test]
+    fn test_geometric_sum() {
+        assert_eq!(
+            Line::parse("1 2 4 geomsum").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![15]))
+        );
+        assert_eq!(
+            Line::parse("3 1 5 geomsum").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![15]))
+        );
+        assert_eq!(
+            Line::parse("1 1 2 / 3 geomsum 2 -1 0 geomsum")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(7, 4), Rational64::from(0)]))
+        );
+        for line in ["1 2 1 2 / geomsum", "1 2 -1 geomsum"] {
+            assert_eq!(
+                Line::parse(line).unwrap().1.calc(Stack::new()),
+                Err(CalcError::MathError)
+            );
+        }
+        assert_eq!(
+            Line::parse("1 2 64 geomsum").unwrap().1.calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_present_value() {
+        assert_eq!(
+            Line::parse("100 1 10 / 1 pv").unwrap().1.calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(1000, 11)]))
+        );
+        assert_eq!(
+            Line::parse("121 1 10 / 2 pv 50 5 100 / 0 pv")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![100, 50]))
+        );
+        for line in ["100 -1 2 pv", "100 1 10 / 1 2 / pv", "100 1 10 / -1 pv"] {
+            assert_eq!(
+                Line::parse(line).unwrap().1.calc(Stack::new()),
+                Err(CalcError::MathError)
+            );
+        }
+    }
+
+    #[test]
+    fn test_poly_deriv() {
+        assert_eq!(
+            Line::parse("1 -3 2 polyderiv")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![2, -3]))
+        );
+        assert_eq!(
+            Line::parse("1 2 / 0 0 1 polyderiv")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![
+                Rational64::new(3, 2),
+                Rational64::from(0),
+                Rational64::from(0)
+            ]))
+        );
+        assert_eq!(
+            Line::parse("5 polyderiv").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![0]))
+        );
+        assert_eq!(
+            Line::parse("polyderiv").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![0]))
+        );
+    }
+
+    #[test]
+    fn test_parallel_sum() {
+        assert_eq!(
+            Line::parse("2 2 par").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![1]))
+        );
+        assert_eq!(
+            Line::parse("3 6 par").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![2]))
+        );
+        assert_eq!(
+            Line::parse("1 2 3 par").unwrap().1.calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(6, 11)]))
+        );
+        assert_eq!(
+            Line::parse("2 0 par").unwrap().1.calc(Stack::new()),
+            Err(CalcError::DivideByZero)
+        );
+        assert_eq!(
+            Line::parse("2 -2 par").unwrap().1.calc(Stack::new()),
+            Err(CalcError::DivideByZero)
+        );
+        assert_eq!(
+            Line::parse("par").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+        assert_eq!(
+            Line::parse("-9223372036854775808 1 par")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+        assert_eq!(
+            Line::parse("-9223372036854775807 -9223372036854775807 par")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(-9223372036854775807, 2)]))
+        );
+    }
+
+    #[test]
+    fn test_harmonic_and_geometric_mean() {
+        assert_eq!(
+            Line::parse("1 2 4 hmean").unwrap().1.calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(12, 7)]))
+        );
+        assert_eq!(
+            Line::parse("1 0 hmean").unwrap().1.calc(Stack::new()),
+            Err(CalcError::DivideByZero)
+        );
+        assert_eq!(
+            Line::parse("hmean").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+        assert_eq!(
+            Line::parse("-9223372036854775808 1 hmean")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+        assert_eq!(
+            Line::parse("1 4 gmean").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![2]))
+        );
+        assert_eq!(
+            Line::parse("2 9 / 8 9 / gmean")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(4, 9)]))
+        );
+        // Exact mode only objects when there is no exact root.
+        let mut calculator = Calculator::default();
+        calculator.run("exact on").unwrap();
+        assert_eq!(calculator.eval("1 4 gmean"), Ok(Some(Rational64::from(2))));
+        assert!(calculator.eval("c 1 2 gmean").is_err());
+        // Too large a product for a Rational64 still has a mean.
+        let gmean = Line::parse("4611686018427387904 4611686018427387904 gmean")
+            .unwrap()
+            .1
+            .calc(Stack::new())
+            .unwrap();
+        assert!((to_float(gmean.last().unwrap()) / 4611686018427387904.0 - 1.0).abs() < 1e-9);
+        assert_eq!(
+            Line::parse("3 0 gmean").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![0]))
+        );
+        assert_eq!(
+            Line::parse("1 -4 gmean").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_assert() {
+        let mut calculator = Calculator::default();
+        calculator.run("1 2 3 4 /").unwrap();
+        assert_eq!(
+            calculator.run("assert 1 2 3 4 /"),
+            Ok("Assertion passed\n".to_string())
+        );
+        assert_eq!(calculator.failed_assertions, 0);
+        assert_eq!(
+            calculator.run("assert 1 3"),
+            Err("Assertion failed!\n- expected: 1 3\n+ actual:   1 2 3/4\n".to_string())
+        );
+        assert_eq!(calculator.failed_assertions, 1);
+        assert_eq!(calculator.stack.0.len(), 3);
+        calculator.run("c").unwrap();
+        assert!(calculator.run("assert").is_ok());
+    }
+
+    #[test]
+    fn test_history() {
+        let mut calculator = Calculator::default();
+        calculator.run("1 2 +").unwrap();
+        calculator.run("4 *").unwrap();
+        calculator.run("dump").unwrap();
+        assert!(calculator.run("+").is_err());
+        assert_eq!(
+            calculator.run("history"),
+            Ok("1: 1 2 +\n2: 4 *\n3: dump\n".to_string())
+        );
+        assert_eq!(
+            calculator.run("!2"),
+            Ok("Stack: 48, Result: 48\n".to_string())
+        );
+        assert_eq!(calculator.history.back().unwrap(), "4 *");
+        assert_eq!(calculator.history.len(), 4);
+        assert!(calculator.run("!9").is_err());
+        assert!(calculator.run("!x").is_err());
+
+        let mut calculator = Calculator::new(
+            Settings::from_args(vec!["--history-size".to_string(), "2".to_string()]).unwrap(),
+        );
+        calculator.run("1").unwrap();
+        calculator.run("2").unwrap();
+        calculator.run("3").unwrap();
+        assert_eq!(calculator.run("history"), Ok("1: 2\n2: 3\n".to_string()));
+    }
+
+    #[test]
+    fn test_mediant() {
+        assert_eq!(
+            Line::parse("1 2 / 1 3 / mediant")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::new(2, 5)])
+        );
+        assert_eq!(
+            Line::parse("2 4 / 1 3 / mediant")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::new(2, 5)])
+        );
+        assert_eq!(
+            Line::parse("0 1 mediant").unwrap().1.calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(1, 2)]))
+        );
+    }
+
+    #[test]
+    fn test_bounded_denominator_approximation() {
+        assert_eq!(
+            Line::parse("355 113 / 10 approx")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::new(22, 7)])
+        );
+        assert_eq!(
+            Line::parse("355 113 / 1000 approx")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::new(355, 113)])
+        );
+        assert_eq!(
+            Line::parse("-355 113 / 10 approx")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack(vec![Rational64::new(-22, 7)])
+        );
+        assert_eq!(
+            Line::parse("1 3 / 1 approx")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack::from(vec![0])
+        );
+        assert_eq!(
+            Line::parse("1 3 / 0 approx").unwrap().1.calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+        assert_eq!(
+            Line::parse("1 3 / 1 2 / approx")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::MathError)
+        );
+    }
+
+    #[test]
+    fn test_timing() {
+        let line = Line::parse("3 6 + 2 *").unwrap().1;
+        let (result, elapsed) = timed(true, || line.calc(Stack::new()));
+        assert_eq!(result, line.calc(Stack::new()));
+        assert!(elapsed.is_some());
+        let (result, elapsed) = timed(false, || line.calc(Stack::new()));
+        assert_eq!(result, Ok(Stack::from(vec![18])));
+        assert_eq!(elapsed, None);
+
+        let mut calculator = Calculator::default();
+        calculator.run("time on").unwrap();
+        let output = calculator.run("1 2 +").unwrap();
+        assert!(output.starts_with("Stack: 3, Result: 3\nTime: "));
+        calculator.run("time off").unwrap();
+        assert_eq!(
+            calculator.run("1 +"),
+            Ok("Stack: 4, Result: 4\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_echo() {
+        let mut calculator =
+            Calculator::new(Settings::from_args(vec!["--echo".to_string()]).unwrap());
+        assert_eq!(
+            calculator.run("3   6+"),
+            Ok("3 6 + => 9\nStack: 9, Result: 9\n".to_string())
+        );
+        calculator.run("mode infix").unwrap();
+        assert_eq!(
+            calculator.run("(1 + 2) * -4"),
+            Ok("1 2 + -4 * => -12\nStack: 9 -12, Result: -12\n".to_string())
+        );
+        calculator.run("mode rpn").unwrap();
+        calculator.run("echo off").unwrap();
+        assert_eq!(calculator.run("c"), Ok(String::new()));
+        calculator.run("echo on").unwrap();
+        assert_eq!(
+            calculator.run("1 2 divmod sorted? ."),
+            Ok("1 2 divmod sorted? . => 1\n1\nStack: 0 1, Result: 1\n".to_string())
+        );
+        assert_eq!(
+            calculator.run("c +"),
+            Err("c +\nStack: 0 1, error[E_STACK]: Not enough items in stack!\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_denominator_limit() {
+        let mut calculator = Calculator::new(
+            Settings::from_args(vec![
+                "--output-denominator-limit".to_string(),
+                "100".to_string(),
+            ])
+            .unwrap(),
+        );
+        assert_eq!(
+            calculator.eval("1 3 / 1 7 / +"),
+            Ok(Some(Rational64::new(10, 21)))
+        );
+        assert_eq!(
+            calculator.eval("1 11 / 1 13 / +"),
+            Err(CalcError::DenominatorTooLarge(143))
+        );
+        assert_eq!(calculator.stack, Stack(vec![Rational64::new(10, 21)]));
+        assert_eq!(
+            Calculator::default().eval("1 11 / 1 13 / +"),
+            Ok(Some(Rational64::new(24, 143)))
+        );
+        assert!(Settings::from_args(vec![
+            "--output-denominator-limit".to_string(),
+            "many".to_string()
+        ])
+        .is_err());
+    }
+
+    #[cfg(feature = "clipboard")]
+    #[test]
+    fn test_copy() {
+        let mut calculator = Calculator::default();
+        calculator.run("1 2 3 4 /").unwrap();
+        let mut copied = String::new();
+        assert_eq!(
+            calculator.copy(|text| {
+                copied = text.to_string();
+                Ok(())
+            }),
+            Ok("Copied 3/4\n".to_string())
+        );
+        assert_eq!(copied, "3/4");
+        assert_eq!(
+            calculator.copy(|_| Err("no clipboard".to_string())),
+            Err("no clipboard\n".to_string())
+        );
+        calculator.run("c").unwrap();
+        assert_eq!(
+            calculator.copy(|_| Ok(())),
+            Err("Nothing to copy\n".to_string())
+        );
+    }
+
+    #[cfg(not(feature = "clipboard"))]
+    #[test]
+    fn test_copy_without_feature() {
+        let mut calculator = Calculator::default();
+        calculator.run("7").unwrap();
+        assert_eq!(
+            calculator.run("copy"),
+            Err("Clipboard support not enabled (build with --features clipboard)\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_fold() {
+        let mut calculator = Calculator::default();
+        calculator.run("def add2 +").unwrap();
+        assert_eq!(
+            calculator.eval("1 2 3 4 fold add2"),
+            Ok(Some(Rational64::from(10)))
+        );
+        calculator.run("def sub2 -").unwrap();
+        // Left to right, so ((10 - 1) - 2) - 3.
+        assert_eq!(
+            calculator.eval("1 2 3 fold sub2"),
+            Ok(Some(Rational64::from(4)))
+        );
+        assert_eq!(
+            calculator.eval("fold nothing"),
+            Err(CalcError::UnknownWord("nothing".to_string()))
+        );
+        calculator.run("def keep").unwrap();
+        assert_eq!(
+            calculator.eval("5 fold keep"),
+            Err(CalcError::MacroArity("keep".to_string()))
+        );
+        assert_eq!(calculator.stack, Stack::from(vec![4]));
+        // Macros are resolved when the line is defined, as usual.
+        calculator.run("def total fold add2").unwrap();
+        calculator.run("def add2 *").unwrap();
+        assert_eq!(calculator.eval("2 3 total"), Ok(Some(Rational64::from(9))));
+        assert_eq!(
+            Calculator::default().eval("fold add2"),
+            Err(CalcError::UnknownWord("add2".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_map() {
+        let mut calculator = Calculator::default();
+        calculator.run("def sq dup *").unwrap();
+        calculator.run("1 2 3 map sq").unwrap();
+        assert_eq!(calculator.stack, Stack::from(vec![1, 4, 9]));
+        assert_eq!(calculator.eval("c map sq"), Ok(None));
+        calculator.run("def two 2").unwrap();
+        assert_eq!(
+            calculator.eval("1 map two"),
+            Err(CalcError::MacroArity("two".to_string()))
+        );
+        calculator.run("def gone p").unwrap();
+        assert_eq!(
+            calculator.eval("1 map gone"),
+            Err(CalcError::MacroArity("gone".to_string()))
+        );
+        calculator.run("def add +").unwrap();
+        assert_eq!(
+            calculator.eval("1 2 map add"),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+        assert_eq!(calculator.stack, Stack::new());
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let sandboxed = || Settings::from_args(vec!["--sandbox".to_string()]).unwrap();
+        let mut calculator = Calculator::new(sandboxed());
+        calculator.run("1 2 +").unwrap();
+        assert_eq!(
+            calculator.run("copy"),
+            Err("copy is disabled in sandbox mode\n".to_string())
+        );
+        assert_eq!(calculator.stack, Stack::from(vec![3]));
+        assert!(Calculator::default().check_sandbox("copy").is_ok());
+        assert!(Settings::from_args(vec![
+            "--sandbox".to_string(),
+            "--init".to_string(),
+            "calcrc".to_string()
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_group_digits() {
+        assert_eq!(group_digits(1234567, ','), "1,234,567");
+        assert_eq!(group_digits(-1000, ','), "-1,000");
+        assert_eq!(group_digits(999, ','), "999");
+        assert_eq!(group_digits(0, ','), "0");
+        assert_eq!(group_digits(100000, '_'), "100_000");
+        assert_eq!(group_digits(i64::MIN, ','), "-9,223,372,036,854,775,808");
+    }
+
+    #[test]
+    fn test_grouped_output() {
+        let mut calculator = Calculator::default();
+        calculator.run("group on").unwrap();
+        assert_eq!(
+            calculator.run("1000 1 2 / 1234567"),
+            Ok("Stack: 1,000 1/2 1,234,567, Result: 1,234,567\n".to_string())
+        );
+        calculator.run("group off").unwrap();
+        assert_eq!(
+            calculator.run("+"),
+            Ok("Stack: 1000 1234567+1/2, Result: 2469135/2\n".to_string())
+        );
+        let settings = Settings::from_args(vec!["--group-separator".to_string(), " ".to_string()]);
+        assert_eq!(settings.unwrap().grouping(), Some(' '));
+        assert!(
+            Settings::from_args(vec!["--group-separator".to_string(), "ab".to_string()]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_result_line() {
+        let mut calculator = Calculator::default();
+        assert_eq!(calculator.result_line(), "RESULT: <empty>");
+        calculator.run("3 6 + 1 2 /").unwrap();
+        assert_eq!(calculator.result_line(), "RESULT: 1/2");
+        let mut calculator = Calculator::new(
+            Settings::from_args(vec!["--result-marker".to_string(), "=".to_string()]).unwrap(),
+        );
+        calculator.run("9").unwrap();
+        assert!(calculator.settings.result_line);
+        assert_eq!(calculator.result_line(), "= 9");
+    }
+
+    #[test]
+    fn test_max_input_length() {
+        let mut calculator = Calculator::new(
+            Settings::from_args(vec!["--max-input-length".to_string(), "10".to_string()]).unwrap(),
+        );
+        assert_eq!(
+            calculator.run("1 2 3 4 5 +"),
+            Err("error[E_LENGTH]: Input is longer than 10 bytes!\n".to_string())
+        );
+        assert_eq!(calculator.stack, Stack::new());
+        assert_eq!(calculator.run("history"), Ok(String::new()));
+        assert_eq!(
+            calculator.run("1 2 3 +"),
+            Ok("Stack: 1 5, Result: 5\n".to_string())
+        );
+        assert_eq!(
+            calculator.eval("1111111111 1"),
+            Err(CalcError::InputTooLong(10))
+        );
+        let huge = "1 ".repeat(1 << 20);
+        assert_eq!(
+            Calculator::default().eval(&huge),
+            Err(CalcError::InputTooLong(1 << 20))
+        );
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let mut calculator = Calculator::new(
+            Settings::from_args(vec!["--max-depth".to_string(), "3".to_string()]).unwrap(),
+        );
+        let line = Line::parse("1 2 3 4").unwrap().1;
+        assert_eq!(
+            line.calc_with(Stack::new(), &mut calculator),
+            Err(CalcError::StackOverflow)
+        );
+        assert_eq!(
+            Line::parse("1 2 3 + 4")
+                .unwrap()
+                .1
+                .calc_with(Stack::new(), &mut calculator),
+            Ok(Stack::from(vec![1, 5, 4]))
+        );
+        calculator.run("maxdepth 5").unwrap();
+        assert_eq!(
+            line.calc_with(Stack::new(), &mut calculator),
+            Ok(Stack::from(vec![1, 2, 3, 4]))
+        );
+        assert!(calculator.run("maxdepth lots").is_err());
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut calculator = Calculator::default();
+        assert_eq!(
+            Line::parse("5 peek 1 2 / peek")
+                .unwrap()
+                .1
+                .calc_with(Stack::new(), &mut calculator),
+            Ok(Stack(vec![
+                Rational64::from(5),
+                Rational64::from(5),
+                Rational64::new(1, 2),
+                Rational64::new(1, 2)
+            ]))
+        );
+        assert_eq!(calculator.emitted, vec!["5", "1/2"]);
+        assert_eq!(
+            Line::parse("peek").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+    }
+
+    #[test]
+    fn test_float_error() {
+        // The nearest f64 to 1/10 is slightly larger than it.
+        assert_eq!(
+            Line::parse("1 10 / floaterr").unwrap().1.calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(-1, 180143985094819840)]))
+        );
+        assert_eq!(
+            Line::parse("1 2 / floaterr 3 floaterr")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![0, 0]))
+        );
+        assert_eq!(exact_float(0.375), Some(Rational64::new(3, 8)));
+        assert_eq!(exact_float(f64::NAN), None);
+    }
+
+    #[test]
+    fn test_convergents() {
+        assert_eq!(
+            Line::parse("103993 33102 / conv")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![
+                Rational64::from(3),
+                Rational64::new(22, 7),
+                Rational64::new(333, 106),
+                Rational64::new(355, 113),
+                Rational64::new(103993, 33102),
+            ]))
+        );
+        assert_eq!(
+            Line::parse("1 -7 4 / conv").unwrap().1.calc(Stack::new()),
+            Ok(Stack(vec![
+                Rational64::from(1),
+                Rational64::from(-2),
+                Rational64::new(-7, 4),
+            ]))
+        );
+        assert_eq!(
+            Line::parse("5 conv").unwrap().1.calc(Stack::new()),
+            Ok(Stack::from(vec![5]))
+        );
+        assert_eq!(
+            Line::parse("conv").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+    }
+
+    #[test]
+    fn test_emit() {
+        let mut calculator = Calculator::default();
+        assert_eq!(
+            Line::parse("1 2 .")
+                .unwrap()
+                .1
+                .calc_with(Stack::new(), &mut calculator),
+            Ok(Stack::from(vec![1]))
+        );
+        assert_eq!(calculator.emitted, vec!["2"]);
+        assert_eq!(
+            Line::parse(".").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
+        );
+
+        let mut calculator = Calculator::default();
+        assert_eq!(
+            calculator.run("1 2 . 3 4 / . +"),
+            Err("2\n3/4\nStack: , error[E_STACK]: Not enough items in stack!\n".to_string())
+        );
+        assert_eq!(
+            calculator.run("1 2 . 3 +"),
+            Ok("2\nStack: 4, Result: 4\n".to_string())
+        );
+        assert!(calculator.emitted.is_empty());
+    }
+
+    #[test]
+    fn test_print_stack() {
+        let mut calculator = Calculator::default();
+        assert_eq!(
+            Line::parse("1 2 3 .s")
+                .unwrap()
+                .1
+                .calc_with(Stack::new(), &mut calculator),
+            Ok(Stack::from(vec![1, 2, 3]))
+        );
+        assert_eq!(calculator.emitted, vec!["1 2 3"]);
+        assert_eq!(
+            Line::parse(".s.")
+                .unwrap()
+                .1
+                .calc_with(Stack::from(vec![4]), &mut calculator),
+            Ok(Stack::new())
+        );
+        assert_eq!(calculator.emitted, vec!["1 2 3", "4", "4"]);
+    }
+
     #[test]
-    fn test_number_parsing() {
+    fn test_divmod() {
         assert_eq!(
-            Item::parse("32"),
-            Ok(("", Item::Num(Rational64::from_integer(32))))
+            Line::parse("17 5 divmod")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack::from(vec![3, 2])
         );
         assert_eq!(
-            Item::parse("-73"),
-            Ok(("", Item::Num(Rational64::from_integer(-73))))
+            Line::parse("-17 5 divmod")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack::from(vec![-4, 3])
+        );
+        assert_eq!(
+            Line::parse("17 -5 divmod")
+                .unwrap()
+                .1
+                .calc(Stack::new())
+                .unwrap(),
+            Stack::from(vec![-4, -3])
+        );
+        assert_eq!(
+            Line::parse("17 0 divmod").unwrap().1.calc(Stack::new()),
+            Err(CalcError::DivideByZero)
+        );
+        assert_eq!(
+            Line::parse("-9223372036854775807 3 divmod")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![-3074457345618258603, 2]))
+        );
+        assert_eq!(
+            Line::parse("1 -9223372036854775808 divmod")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Err(CalcError::Overflow)
+        );
+        assert_eq!(
+            Line::parse("7 2 / 3 4 / divmod")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![Rational64::from(4), Rational64::new(1, 2)]))
         );
     }
+
     #[test]
-    fn test_operator_parsing() {
-        assert_eq!(Item::parse("+"), Ok(("", Item::Operator(Operator::Add))));
-        assert_eq!(Item::parse("/"), Ok(("", Item::Operator(Operator::Divide))));
+    fn test_reset() {
+        let mut calculator = Calculator::default();
+        calculator.run("mode infix").unwrap();
+        calculator.run("def twice 2 *").unwrap();
+        calculator.run("mode rpn").unwrap();
+        calculator.run("exact on").unwrap();
+        calculator.run("1 2 3 twice").unwrap();
+        assert!(calculator.run("assert 1").is_err());
+        calculator.run("reset").unwrap();
+        assert_eq!(calculator.stack, Stack::new());
+        assert!(calculator.macros.is_empty());
+        assert_eq!(calculator.run("history"), Ok(String::new()));
+        assert_eq!(calculator.failed_assertions, 0);
+        assert!(calculator.run("4 twice").is_err());
+        assert!(calculator.settings.exact);
+    }
+
+    #[test]
+    fn test_eval_line_by_line() {
+        let mut calculator = Calculator::default();
+        let lines = ["1 2 +", "+", "4 *", "2 ."];
         assert_eq!(
-            Item::parse("-"),
-            Ok(("", Item::Operator(Operator::Subtract)))
+            lines.map(|line| calculator.eval(line)),
+            [
+                Ok(Some(Rational64::from(3))),
+                Err(CalcError::NotEnoughItemsInStack),
+                Ok(Some(Rational64::from(12))),
+                Ok(Some(Rational64::from(12))),
+            ]
         );
+        assert_eq!(calculator.stack, Stack::from(vec![12]));
+
+        calculator.run("mode infix").unwrap();
         assert_eq!(
-            Item::parse("*"),
-            Ok(("", Item::Operator(Operator::Multiply)))
+            ["(1 +", "p"].map(|line| calculator.eval(line)),
+            [
+                Err(CalcError::ParseError("(1 +".to_string())),
+                Err(CalcError::ParseError("p".to_string()))
+            ]
         );
     }
+
     #[test]
-    fn test_single_item_parsing() {
+    fn test_settings_command() {
+        let mut calculator = Calculator::default();
+        let listing = calculator.run("settings").unwrap();
+        assert!(listing.starts_with("mode = rpn\ndisplay = bottom\n"));
+        assert!(listing.contains("\nmax-depth = 1000000\n"));
+        assert!(listing.contains("\nround-mode = half-up\n"));
+        assert!(listing.contains("\nenable-ops = all\n"));
+        assert!(listing.contains("\ndisable-ops = none\n"));
+
+        calculator.run("mode infix").unwrap();
+        calculator.run("maxdepth 10").unwrap();
+        calculator.run("roundmode half-even").unwrap();
+        calculator.run("group on").unwrap();
+        let listing = calculator.run("settings").unwrap();
+        assert!(listing.starts_with("mode = infix\n"));
+        assert!(listing.contains("\nmax-depth = 10\n"));
+        assert!(listing.contains("\nround-mode = half-even\n"));
+        assert!(listing.contains("\ngroup = on\n"));
+        assert!(listing.lines().all(|line| line.contains(" = ")));
+    }
+
+    #[test]
+    fn test_enabled_and_disabled_ops() {
+        let args = |args: &[&str]| Settings::from_args(args.iter().map(|a| a.to_string()));
+
+        let mut calculator = Calculator::new(args(&["--enable-ops", "+"]).unwrap());
         assert_eq!(
-            Line::parse("3"),
-            Ok(("", Line(vec![Item::Num(Rational64::from_integer(3))])))
+            calculator.eval("3 4 *"),
+            Err(CalcError::ParseError("*".to_string()))
         );
+        assert_eq!(calculator.eval("3 4 +"), Ok(Some(Rational64::from(7))));
+        calculator.run("mode infix").unwrap();
         assert_eq!(
-            Line::parse("+"),
-            Ok(("", Line(vec![Item::Operator(Operator::Add)])))
+            calculator.eval("2 * 3"),
+            Err(CalcError::ParseError("*".to_string()))
         );
+
+        let mut calculator = Calculator::new(args(&["--enable-ops", "+-*/"]).unwrap());
         assert_eq!(
-            Line::parse("-"),
-            Ok(("", Line(vec![Item::Operator(Operator::Subtract)])))
+            calculator.eval("6 2 - 2 / 3 *"),
+            Ok(Some(Rational64::from(6)))
         );
-        assert_eq!(
-            Line::parse("*"),
-            Ok(("", Line(vec![Item::Operator(Operator::Multiply)])))
+        assert!(calculator.eval("2 3 ^").is_err());
+
+        let mut calculator = Calculator::new(args(&["--disable-ops", "sort, fold"]).unwrap());
+        assert!(calculator.eval("3 1 2 sort").is_err());
+        assert!(calculator.run("def add + ").is_ok());
+        assert!(calculator.eval("1 2 3 fold add").is_err());
+        assert_eq!(calculator.eval("1 2 3 S"), Ok(Some(Rational64::from(6))));
+
+        assert!(args(&["--enable-ops", "+ nope"]).is_err());
+    }
+
+    #[test]
+    fn test_radix() {
+        let mut calculator = Calculator::default();
+        assert_eq!(calculator.eval("10 1 +"), Ok(Some(Rational64::from(11))));
+        assert!(calculator.run("ff").is_err());
+
+        let mut calculator = Calculator::new(
+            Settings::from_args(vec!["--radix".to_string(), "16".to_string()]).unwrap(),
         );
         assert_eq!(
-            Line::parse("/"),
-            Ok(("", Line(vec![Item::Operator(Operator::Divide)])))
+            calculator.run("ff 1 +"),
+            Ok("Stack: 256, Result: 256\n".to_string())
         );
         assert_eq!(
-            Line::parse("S"),
-            Ok(("", Line(vec![Item::Operator(Operator::Sum)])))
+            calculator.eval("p c -FF +"),
+            Ok(Some(Rational64::from(-243)))
         );
+        assert_eq!(calculator.eval("+1a +"), Ok(Some(Rational64::from(-217))));
+        // Operators that read as numbers are still there behind an `@`.
+        calculator.run("@c").unwrap();
+        assert_eq!(calculator.stack, Stack::new());
+        assert_eq!(calculator.eval("5 2 @ff"), Ok(Some(Rational64::from(20))));
+        assert_eq!(calculator.eval("@c 3 dup *"), Ok(Some(Rational64::from(9))));
         assert_eq!(
-            Line::parse("-"),
-            Ok(("", Line(vec![Item::Operator(Operator::Subtract)])))
+            calculator.eval("fg"),
+            Err(CalcError::ParseError("fg".to_string()))
         );
         assert_eq!(
-            Line::parse("^"),
-            Ok(("", Line(vec![Item::Operator(Operator::Power)])))
+            calculator.eval("1 0fg"),
+            Err(CalcError::ParseError("0fg".to_string()))
         );
-    }
-    #[test]
-    fn test_multiple_item_parsing() {
+        assert!(calculator.run("def face 1 +").is_err());
+        calculator.run("def inc 1 +").unwrap();
+        assert_eq!(calculator.eval("@c 1 inc"), Ok(Some(Rational64::from(2))));
+
+        calculator.run("radix 2").unwrap();
+        // Outside hex, c is clear again.
         assert_eq!(
-            Line::parse("3 6 +"),
-            Ok((
-                "",
-                Line(vec![
-                    Item::Num(Rational64::from_integer(3)),
-                    Item::Num(Rational64::from_integer(6)),
-                    Item::Operator(Operator::Add)
-                ])
-            ))
+            calculator.eval("c 101 -11 *"),
+            Ok(Some(Rational64::from(-15)))
         );
         assert_eq!(
-            Line::parse("47 9 + 15 *"),
-            Ok((
-                "",
-                Line(vec![
-                    Item::Num(Rational64::from_integer(47)),
-                    Item::Num(Rational64::from_integer(9)),
-                    Item::Operator(Operator::Add),
-                    Item::Num(Rational64::from_integer(15)),
-                    Item::Operator(Operator::Multiply)
-                ])
-            ))
+            calculator.eval("12"),
+            Err(CalcError::ParseError("12".to_string()))
         );
+        assert!(calculator.run("radix 37").is_err());
+        assert_eq!(calculator.settings.radix, 2);
+        assert!(Settings::from_args(vec!["--radix".to_string(), "1".to_string()]).is_err());
     }
+
     #[test]
-    fn test_multiple_item_parsing_with_weird_spaces() {
-        assert_eq!(
-            Line::parse("3 6-2**"),
-            Ok((
-                "",
-                Line(vec![
-                    Item::Num(Rational64::from_integer(3)),
-                    Item::Num(Rational64::from_integer(6)),
-                    Item::Num(Rational64::from_integer(-2)),
-                    Item::Operator(Operator::Multiply),
-                    Item::Operator(Operator::Multiply)
-                ])
-            ))
-        );
+    fn test_strict_tokens() {
+        let strict = Settings {
+            strict_tokens: true,
+            ..Default::default()
+        };
+        assert!(Line::parse_with("6-2", &strict).is_err());
+        assert!(Line::parse_with("3 6-2**", &strict).is_err());
+        assert!(Line::parse_with("6 2*", &strict).is_err());
         assert_eq!(
-            Line::parse(" 3 6*2* "),
+            Line::parse_with("6 - 2", &strict),
             Ok((
                 "",
                 Line(vec![
-                    Item::Num(Rational64::from_integer(3)),
                     Item::Num(Rational64::from_integer(6)),
-                    Item::Operator(Operator::Multiply),
+                    Item::Operator(Operator::Subtract),
                     Item::Num(Rational64::from_integer(2)),
-                    Item::Operator(Operator::Multiply)
                 ])
             ))
         );
         assert_eq!(
-            Line::parse(" 3 6+2** "),
+            Line::parse_with("6 -2\n", &strict),
             Ok((
                 "",
                 Line(vec![
-                    Item::Num(Rational64::from_integer(3)),
                     Item::Num(Rational64::from_integer(6)),
-                    Item::Num(Rational64::from_integer(2)),
-                    Item::Operator(Operator::Multiply),
-                    Item::Operator(Operator::Multiply)
+                    Item::Num(Rational64::from_integer(-2)),
                 ])
             ))
         );
         assert_eq!(
-            Line::parse("3 6 2 S"),
+            Line::parse("6-2"),
             Ok((
                 "",
                 Line(vec![
-                    Item::Num(Rational64::from_integer(3)),
                     Item::Num(Rational64::from_integer(6)),
-                    Item::Num(Rational64::from_integer(2)),
-                    Item::Operator(Operator::Sum),
+                    Item::Num(Rational64::from_integer(-2)),
                 ])
             ))
         );
+
+        let mut calculator =
+            Calculator::new(Settings::from_args(vec!["--strict-tokens".to_string()]).unwrap());
         assert_eq!(
-            Line::parse("3 2 ^"),
-            Ok((
-                "",
-                Line(vec![
-                    Item::Num(Rational64::from_integer(3)),
-                    Item::Num(Rational64::from_integer(2)),
-                    Item::Operator(Operator::Power),
-                ])
-            ))
+            calculator.run("3 6-2"),
+            Err("error[E_PARSE]: Parsing Error at `-2`!\n".to_string())
         );
+        assert_eq!(calculator.stack, Stack::new());
     }
+
     #[test]
-    fn test_calculating_simple_integers() {
+    fn test_show_percent() {
+        let mut calculator = Calculator::default();
         assert_eq!(
-            Line::parse("3 6 +").unwrap().1.calc(Stack::new()).unwrap(),
-            Stack(vec![Rational64::from_integer(9)])
+            Line::parse("1 4 / pct% 1 3 / pct% 2 3 / pct% -1 8 / pct%")
+                .unwrap()
+                .1
+                .calc_with(Stack::new(), &mut calculator),
+            Ok(Stack(vec![
+                Rational64::new(1, 4),
+                Rational64::new(1, 3),
+                Rational64::new(2, 3),
+                Rational64::new(-1, 8),
+            ]))
         );
         assert_eq!(
-            Line::parse("3 6 *").unwrap().1.calc(Stack::new()).unwrap(),
-            Stack(vec![Rational64::from_integer(18)])
+            calculator.emitted,
+            vec!["25%", "33.33%", "66.67%", "-12.50%"]
         );
         assert_eq!(
-            Line::parse("3 6 + 2 *")
-                .unwrap()
-                .1
-                .calc(Stack::new())
-                .unwrap(),
-            Stack(vec![Rational64::from_integer(18)])
+            Line::parse("pct%").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
         );
+    }
+
+    #[test]
+    fn test_show_base() {
+        let mut calculator = Calculator::default();
         assert_eq!(
-            Line::parse("6 3 - 2 -")
+            Line::parse("255 16 base 10 2 base -35 36 base 0 8 base")
                 .unwrap()
                 .1
-                .calc(Stack::new())
-                .unwrap(),
-            Stack(vec![Rational64::from_integer(1)])
+                .calc_with(Stack::new(), &mut calculator),
+            Ok(Stack::from(vec![255, 10, -35, 0]))
         );
+        assert_eq!(calculator.emitted, vec!["ff", "1010", "-z", "0"]);
+        assert_eq!(format_base(i64::MIN, 2), format!("-1{}", "0".repeat(63)));
+        for line in ["10 1 base", "10 37 base", "1 2 / 2 base", "10 1 2 / base"] {
+            assert_eq!(
+                Line::parse(line).unwrap().1.calc(Stack::new()),
+                Err(CalcError::MathError)
+            );
+        }
         assert_eq!(
-            Line::parse("6 -3 - -2 -")
-                .unwrap()
-                .1
-                .calc(Stack::new())
-                .unwrap(),
-            Stack(vec![Rational64::from_integer(11)])
+            Line::parse("16 base").unwrap().1.calc(Stack::new()),
+            Err(CalcError::NotEnoughItemsInStack)
         );
+    }
+
+    #[test]
+    fn test_format_decimal() {
+        let mode = RoundMode::HalfUp;
         assert_eq!(
-            Line::parse("6 +3 - -2 *")
-                .unwrap()
-                .1
-                .calc(Stack::new())
-                .unwrap(),
-            Stack(vec![Rational64::from_integer(-6)])
+            format_decimal(&Rational64::new(1, 3), 2, mode).unwrap(),
+            "0.33"
         );
         assert_eq!(
-            Line::parse("2 4 6 S")
-                .unwrap()
-                .1
-                .calc(Stack::new())
-                .unwrap(),
-            Stack(vec![Rational64::from_integer(12)])
+            format_decimal(&Rational64::new(-1, 200), 2, mode).unwrap(),
+            "-0.01"
         );
         assert_eq!(
-            Line::parse("3 2 ^").unwrap().1.calc(Stack::new()).unwrap(),
-            Stack(vec![Rational64::from_integer(9)])
+            format_decimal(&Rational64::new(5, 2), 0, mode).unwrap(),
+            "3"
+        );
+        assert_eq!(
+            format_decimal(&Rational64::new(5, 2), 0, RoundMode::HalfEven).unwrap(),
+            "2"
+        );
+        assert_eq!(
+            format_decimal(&Rational64::from(7), 3, mode).unwrap(),
+            "7.000"
         );
     }
+
     #[test]
-    fn test_op_on_empty_stack() {
+    fn test_round_modes() {
+        // (mode, 5/2, 7/2, -5/2, 1/3)
+        let cases = [
+            (RoundMode::HalfUp, 3, 4, -3, 0),
+            (RoundMode::HalfEven, 2, 4, -2, 0),
+            (RoundMode::TowardZero, 2, 3, -2, 0),
+            (RoundMode::AwayFromZero, 3, 4, -3, 1),
+        ];
+        for (mode, five_halves, seven_halves, minus_five_halves, third) in cases {
+            assert_eq!(
+                round(&Rational64::new(5, 2), mode),
+                Ok(Rational64::from(five_halves)),
+                "{mode:?}"
+            );
+            assert_eq!(
+                round(&Rational64::new(7, 2), mode),
+                Ok(Rational64::from(seven_halves)),
+                "{mode:?}"
+            );
+            assert_eq!(
+                round(&Rational64::new(-5, 2), mode),
+                Ok(Rational64::from(minus_five_halves)),
+                "{mode:?}"
+            );
+            assert_eq!(
+                round(&Rational64::new(1, 3), mode),
+                Ok(Rational64::from(third)),
+                "{mode:?}"
+            );
+        }
+
+        // Ratio's own rounding overflows this close to the limits.
+        let half = i64::MAX / 2;
+        let cases = [
+            (RoundMode::HalfUp, half + 1, -half - 1),
+            (RoundMode::HalfEven, half + 1, -half - 1),
+            (RoundMode::TowardZero, half, -half),
+            (RoundMode::AwayFromZero, half + 1, -half - 1),
+        ];
+        for (mode, max_halves, minus_max_halves) in cases {
+            assert_eq!(
+                round(&Rational64::new(i64::MAX, 2), mode),
+                Ok(Rational64::from(max_halves)),
+                "{mode:?}"
+            );
+            assert_eq!(
+                round(&Rational64::new(-i64::MAX, 2), mode),
+                Ok(Rational64::from(minus_max_halves)),
+                "{mode:?}"
+            );
+            for n in [i64::MAX, i64::MIN] {
+                assert_eq!(
+                    round(&Rational64::from(n), mode),
+                    Ok(Rational64::from(n)),
+                    "{mode:?}"
+                );
+            }
+        }
+
+        let mut calculator = Calculator::default();
+        calculator.run("roundmode away-from-zero").unwrap();
         assert_eq!(
-            Line::parse("+").unwrap().1.calc(Stack::new()),
-            Err(CalcError::NotEnoughItemsInStack)
+            calculator.eval("9223372036854775807 2 / round"),
+            Ok(Some(Rational64::from(half + 1)))
         );
         assert_eq!(
-            Line::parse("1+").unwrap().1.calc(Stack::new()),
-            Err(CalcError::NotEnoughItemsInStack)
-        )
+            calculator.eval("c 9223372036854775807 3 / 1 ond"),
+            Ok(Some(Rational64::from(3074457345618258603)))
+        );
     }
 
     #[test]
-    fn test_creating_fraction() {
+    fn test_on_denominator() {
         assert_eq!(
-            Line::parse("1 2 /").unwrap().1.calc(Stack::new()).unwrap(),
-            Stack(vec![Rational64::new(1, 2)])
+            Line::parse("1 3 / 2 ond").unwrap().1.calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(1, 2)]))
         );
         assert_eq!(
-            Line::parse("2 4 /").unwrap().1.calc(Stack::new()).unwrap(),
-            Stack(vec![Rational64::new(1, 2)])
-        )
+            Line::parse("1 5 / 4 ond 7 3 / 100 ond")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack(vec![
+                Rational64::new(1, 4),
+                Rational64::new(233, 100)
+            ]))
+        );
+        assert_eq!(
+            Line::parse("-1 3 / 2 ond").unwrap().1.calc(Stack::new()),
+            Ok(Stack(vec![Rational64::new(-1, 2)]))
+        );
+        for line in ["1 0 ond", "1 -2 ond", "1 1 2 / ond"] {
+            assert_eq!(
+                Line::parse(line).unwrap().1.calc(Stack::new()),
+                Err(CalcError::MathError)
+            );
+        }
+
+        assert_eq!(
+            Line::parse("9223372036854775807 3 / 1 ond")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![3074457345618258602]))
+        );
+
+        let mut calculator = Calculator::default();
+        calculator.run("roundmode toward-zero").unwrap();
+        assert_eq!(
+            calculator.eval("1 3 / 2 ond"),
+            Ok(Some(Rational64::from(0)))
+        );
     }
 
     #[test]
-    fn operations_on_fractions() {
+    fn test_sig_fig() {
         assert_eq!(
-            Line::parse("1 2 / 1 2 / +")
+            Line::parse("12345 3 sigfig 1 3 / 2 sigfig")
                 .unwrap()
                 .1
-                .calc(Stack::new())
-                .unwrap(),
-            Stack(vec![Rational64::from_integer(1)])
+                .calc(Stack::new()),
+            Ok(Stack(vec![
+                Rational64::from(12300),
+                Rational64::new(33, 100)
+            ]))
         );
         assert_eq!(
-            Line::parse("1 2 / 1 2 / *")
+            Line::parse("-2 3 / 1 sigfig 999 2 sigfig 0 3 sigfig 7 5 sigfig")
                 .unwrap()
                 .1
-                .calc(Stack::new())
-                .unwrap(),
-            Stack(vec![Rational64::new(1, 4)])
+                .calc(Stack::new()),
+            Ok(Stack(vec![
+                Rational64::new(-7, 10),
+                Rational64::from(1000),
+                Rational64::from(0),
+                Rational64::from(7)
+            ]))
         );
         assert_eq!(
-            Line::parse("1 2 / 1 2 / -")
+            Line::parse("9223372036854775807 2 sigfig")
                 .unwrap()
                 .1
-                .calc(Stack::new())
-                .unwrap(),
-            Stack(vec![Rational64::from_integer(0)])
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![9200000000000000000]))
+        );
+        assert_eq!(
+            Line::parse("-9223372036854775808 1 sigfig 9223372036854775807 3 / 2 sigfig")
+                .unwrap()
+                .1
+                .calc(Stack::new()),
+            Ok(Stack::from(vec![-9000000000000000000, 3100000000000000000]))
+        );
+        for line in ["5 0 sigfig", "5 -1 sigfig", "5 1 2 / sigfig"] {
+            assert_eq!(
+                Line::parse(line).unwrap().1.calc(Stack::new()),
+                Err(CalcError::MathError)
+            );
+        }
+
+        let mut calculator = Calculator::default();
+        calculator.run("roundmode toward-zero").unwrap();
+        assert_eq!(
+            calculator.eval("2 3 / 1 sigfig"),
+            Ok(Some(Rational64::new(3, 5)))
+        );
+    }
+
+    #[test]
+    fn test_round_mode_setting() {
+        let mut calculator = Calculator::new(
+            Settings::from_args(vec!["--round-mode".to_string(), "half-even".to_string()]).unwrap(),
+        );
+        assert_eq!(
+            calculator.eval("5 2 / round"),
+            Ok(Some(Rational64::from(2)))
+        );
+        calculator.run("roundmode toward-zero").unwrap();
+        assert_eq!(
+            calculator.eval("7 2 / round"),
+            Ok(Some(Rational64::from(3)))
+        );
+        // divmod floors whatever the mode.
+        assert_eq!(
+            calculator.eval("c -7 2 divmod"),
+            Ok(Some(Rational64::from(1)))
         );
+        assert_eq!(calculator.stack, Stack::from(vec![-4, 1]));
+        assert!(calculator.run("roundmode sideways").is_err());
+        assert_eq!(calculator.settings.round_mode, RoundMode::TowardZero);
+        assert!(Settings::from_args(vec!["--round-mode".to_string(), "up".to_string()]).is_err());
     }
 }
@@ -0,0 +1,159 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+/// Runs the calculator with the given arguments, feeding it `input` on stdin.
+fn run(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_calc-rs"))
+        .args(args)
+        .env_remove("CALC_PROMPT")
+        .env("HOME", std::env::temp_dir())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// A file in the temp directory, removed again when dropped.
+struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new(name: &str, contents: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("calc-rs-{}-{name}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        TempFile(path)
+    }
+
+    fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[test]
+fn init_file_defines_macros() {
+    let init = TempFile::new("init", "# add three\ndef add3 3 +\nnot a macro\n");
+    let output = run(&["--init", init.path(), "--prompt", ""], "4 add3\n");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Stack: 7, Result: 7\n"
+    );
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains(":3: Stack: , error[E_WORD]: Unknown word not!"));
+}
+
+#[test]
+fn missing_init_file_only_warns() {
+    let output = run(
+        &["--init", "/nonexistent/calcrc", "--prompt", ""],
+        "1 2 +\n",
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Stack: 3, Result: 3\n"
+    );
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .starts_with("Warning: could not read /nonexistent/calcrc"));
+}
+
+#[test]
+fn sandbox_refuses_init_file() {
+    let init = TempFile::new("sandbox-init", "def add3 3 +\n");
+    let output = run(&["--sandbox", "--init", init.path()], "");
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("--init is disabled in sandbox mode"));
+}
+
+#[test]
+fn passing_assertion_script_succeeds() {
+    let output = run(&["--prompt", ""], "3 6 +\nassert 9\n2 *\nassert 18\n");
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .ends_with("Assertion passed\n"));
+}
+
+#[test]
+fn failing_assertion_script_exits_non_zero() {
+    let output = run(&["--prompt", ""], "3 6 +\nassert 10\n1 +\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Stack: 9, Result: 9\nAssertion failed!\n- expected: 10\n+ actual:   9\n"
+    );
+}
+
+#[test]
+fn failing_assertion_in_init_file_only_warns() {
+    let init = TempFile::new("assert-init", "1\nassert 2\n");
+    let output = run(&["--init", init.path(), "--prompt", ""], "c 3 4 +\n2 *\n");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Stack: 7, Result: 7\nStack: 14, Result: 14\n"
+    );
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains(":2: Assertion failed!"));
+}
+
+#[test]
+fn result_line_follows_piped_input() {
+    let output = run(&["--prompt", "", "--result-line"], "3 6 +\n2 /\n");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().last(), Some("RESULT: 9/2"));
+    assert_eq!(
+        stdout.lines().filter(|l| l.starts_with("RESULT:")).count(),
+        1
+    );
+
+    let output = run(&["--prompt", "", "--result-line"], "1\nc\nexit\n");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap().lines().last(),
+        Some("RESULT: <empty>")
+    );
+}
+
+#[test]
+fn help_flag_prints_usage() {
+    for flag in ["--help", "-h"] {
+        let output = run(&[flag], "");
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.starts_with("Usage: calc-rs [OPTIONS]"));
+        assert!(stdout.contains("--infix"));
+        assert!(stdout.contains("Operators:"));
+    }
+}
+
+#[test]
+fn version_flag_prints_version() {
+    for flag in ["--version", "-V"] {
+        let output = run(&[flag], "");
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            format!("calc-rs {}\n", env!("CARGO_PKG_VERSION"))
+        );
+    }
+}